@@ -1,48 +1,210 @@
 use std::env;
+use std::error::Error;
 use std::fs;
 
+/// The environment variable that points at a file of default flags.
+const CONFIG_ENV: &str = "MINIGREP_CONFIG";
+
 pub struct Config {
     pub query: String,
     pub filename: String,
     pub case_sensitive: bool,
+    /// The 1-based column the query applies to, or `None` to match the whole line.
+    pub field: Option<usize>,
+    pub delimiter: char,
+    /// The 1-based columns to print for matching lines, or empty to print the whole line.
+    pub columns: Vec<usize>,
+    /// Whether `--no-config` was given.
+    no_config: bool,
 }
 
 impl Config {
-    pub fn new(mut args: env::Args) -> Result<Config, &'static str> {
-        args.next(); // skip the executable path
+    /// Builds the configuration from the command line arguments.
+    ///
+    /// Flags are applied in the following order, so later sources override earlier ones:
+    /// 1. The file named by `MINIGREP_CONFIG`, one flag per line (skipped by `--no-config`).
+    ///    Unknown options are ignored there, so the file can be shared with other versions.
+    /// 2. The `CASE_INSENSITIVE` environment variable
+    /// 3. The command line arguments
+    pub fn new(args: impl Iterator<Item = String>) -> Result<Config, String> {
+        let args: Vec<String> = args.skip(1).collect(); // skip the executable path
 
-        let query = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a query string"),
+        // The command line is parsed on its own first, so that `--no-config` only counts as a
+        // flag, not after `--` or as the value of another option
+        let no_config = Config::parse(args.clone(), None, false)?.no_config;
+        let defaults = if no_config {
+            None
+        } else {
+            match env::var(CONFIG_ENV) {
+                Ok(path) => Some(
+                    fs::read_to_string(&path)
+                        .map_err(|e| format!("Failed to read config file {}: {}", path, e))?,
+                ),
+                Err(_) => None,
+            }
         };
 
-        let filename = match args.next() {
+        Config::parse(
+            args,
+            defaults.as_deref(),
+            env::var("CASE_INSENSITIVE").is_ok(),
+        )
+    }
+
+    fn parse(
+        args: Vec<String>,
+        defaults: Option<&str>,
+        case_insensitive: bool,
+    ) -> Result<Config, String> {
+        let mut config = Config {
+            query: String::new(),
+            filename: String::new(),
+            case_sensitive: true,
+            field: None,
+            delimiter: ',',
+            columns: vec![],
+            no_config: false,
+        };
+
+        if let Some(defaults) = defaults {
+            for line in defaults.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                if !line.starts_with('-') {
+                    return Err(format!("Invalid line in config file: {}", line));
+                }
+                let mut words = line.splitn(2, char::is_whitespace);
+                let flag = words.next().unwrap();
+                let mut values = words.map(|value| value.trim().to_string());
+                if !config.apply_flag(flag, &mut values)? {
+                    continue;
+                }
+                if values.next().is_some() {
+                    return Err(format!("Unexpected value in config file: {}", line));
+                }
+            }
+        }
+
+        if case_insensitive {
+            config.case_sensitive = false;
+        }
+
+        let mut positional = vec![];
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                positional.extend(args.by_ref());
+            } else if arg.len() > 1 && arg.starts_with('-') {
+                if !config.apply_flag(&arg, &mut args)? {
+                    return Err(format!("Unknown option: {}", arg));
+                }
+            } else {
+                positional.push(arg);
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        config.query = match positional.next() {
             Some(arg) => arg,
-            None => return Err("Didn't get a file name"),
+            None => return Err(String::from("Didn't get a query string")),
         };
+        config.filename = match positional.next() {
+            Some(arg) => arg,
+            None => return Err(String::from("Didn't get a file name")),
+        };
+        if let Some(arg) = positional.next() {
+            return Err(format!("Unexpected argument: {}", arg));
+        }
 
-        let case_sensitive = env::var("CASE_INSENSITIVE").is_err();
+        Ok(config)
+    }
 
-        Ok(Config {
-            query,
-            filename,
-            case_sensitive,
-        })
+    /// Applies a single flag, taking its value from `--flag=value` or else from `values`.
+    ///
+    /// Returns `Ok(false)` without taking any value if the option is unknown.
+    fn apply_flag(
+        &mut self,
+        flag: &str,
+        values: &mut impl Iterator<Item = String>,
+    ) -> Result<bool, String> {
+        let (flag, mut inline) = match flag.split_once('=') {
+            Some((flag, value)) if flag.starts_with("--") => (flag, Some(value.to_string())),
+            _ => (flag, None),
+        };
+        let mut value = || match inline.take() {
+            Some(value) => Ok(value),
+            None => values
+                .next()
+                .ok_or_else(|| format!("Missing value for option: {}", flag)),
+        };
+
+        match flag {
+            "-i" | "--ignore-case" => self.case_sensitive = false,
+            "-s" | "--case-sensitive" => self.case_sensitive = true,
+            "--no-config" => self.no_config = true,
+            "-f" | "--field" => self.field = Some(parse_column(&value()?)?),
+            "-d" | "--delimiter" => self.delimiter = parse_delimiter(&value()?)?,
+            "-c" | "--columns" => {
+                self.columns = value()?
+                    .split(',')
+                    .map(parse_column)
+                    .collect::<Result<_, _>>()?
+            }
+            _ => return Ok(false),
+        }
+        if inline.is_some() {
+            return Err(format!("Option {} doesn't take a value", flag));
+        }
+        Ok(true)
+    }
+}
+
+fn parse_column(value: &str) -> Result<usize, String> {
+    match value.trim().parse::<usize>() {
+        Ok(0) => Err(String::from("Column numbers start at 1")),
+        Ok(column) => Ok(column),
+        Err(_) => Err(format!("Invalid column number: {}", value)),
     }
 }
 
-pub fn run(config: Config) -> Result<(), &'static str> {
-    let contents =
-        fs::read_to_string(config.filename).expect("Something went wrong reading the file");
+fn parse_delimiter(value: &str) -> Result<char, String> {
+    if value == "\\t" || value == "tab" {
+        return Ok('\t');
+    }
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if c != '"' => Ok(c),
+        _ => Err(format!("Invalid delimiter: {}", value)),
+    }
+}
 
-    let results = if config.case_sensitive {
-        search(&config.query, &contents)
-    } else {
-        search_case_insensitive(&config.query, &contents)
+pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
+    let contents = fs::read_to_string(&config.filename)
+        .map_err(|e| format!("Failed to read {}: {}", config.filename, e))?;
+
+    let results = match config.field {
+        Some(field) => search_field(
+            &config.query,
+            &contents,
+            field,
+            config.delimiter,
+            config.case_sensitive,
+        ),
+        None if config.case_sensitive => search(&config.query, &contents),
+        None => search_case_insensitive(&config.query, &contents),
     };
 
     for line in results {
-        println!("{}", line);
+        if config.columns.is_empty() {
+            println!("{}", line);
+        } else {
+            println!(
+                "{}",
+                select_columns(line, &config.columns, config.delimiter)
+            );
+        }
     }
     Ok(())
 }
@@ -62,10 +224,96 @@ pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a st
         .collect()
 }
 
+/// Searches `query` only in the `field`-th (1-based) column of each delimited line.
+///
+/// Fields are split with CSV quoting rules: a field wrapped in double quotes may contain the
+/// delimiter, and `""` inside it stands for a single quote.
+pub fn search_field<'a>(
+    query: &str,
+    contents: &'a str,
+    field: usize,
+    delimiter: char,
+    case_sensitive: bool,
+) -> Vec<&'a str> {
+    let query = if case_sensitive {
+        query.to_string()
+    } else {
+        query.to_lowercase()
+    };
+    // Unquoting only removes quotes, so a line can't have a matching field unless the whole
+    // line matches, which lets the line-based search skip most lines cheaply.
+    let candidates = if query.contains('"') {
+        contents.lines().collect()
+    } else if case_sensitive {
+        search(&query, contents)
+    } else {
+        search_case_insensitive(&query, contents)
+    };
+    candidates
+        .into_iter()
+        .filter(|line| match split_fields(line, delimiter).get(field - 1) {
+            Some(value) if case_sensitive => value.contains(&query),
+            Some(value) => value.to_lowercase().contains(&query),
+            None => false,
+        })
+        .collect()
+}
+
+/// Splits a delimited line into its fields, respecting CSV quoting.
+pub fn split_fields(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    in_quotes = false;
+                }
+            } else {
+                field.push(c);
+            }
+        } else if c == delimiter {
+            fields.push(std::mem::take(&mut field));
+        } else if c == '"' && field.is_empty() {
+            in_quotes = true;
+        } else {
+            field.push(c);
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Joins the given (1-based) columns of a delimited line, quoting them again where needed.
+pub fn select_columns(line: &str, columns: &[usize], delimiter: char) -> String {
+    let fields = split_fields(line, delimiter);
+    columns
+        .iter()
+        .map(|column| {
+            let field = fields.get(column - 1).map(String::as_str).unwrap_or("");
+            if field.contains(delimiter) || field.contains('"') {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            } else {
+                field.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(&delimiter.to_string())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
     #[test]
     fn case_sensitive() {
         let query = "duct";
@@ -92,4 +340,90 @@ Trust me.";
             search_case_insensitive(query, contents)
         );
     }
+
+    #[test]
+    fn field() {
+        let contents = "\
+level,message
+INFO,\"started, rust\"
+WARN,\"said \"\"Rust\"\"\"
+rust,plain";
+
+        assert_eq!(
+            vec!["INFO,\"started, rust\""],
+            search_field("rust", contents, 2, ',', true)
+        );
+        assert_eq!(
+            vec!["INFO,\"started, rust\"", "WARN,\"said \"\"Rust\"\"\""],
+            search_field("RUST", contents, 2, ',', false)
+        );
+        assert_eq!(
+            vec!["WARN,\"said \"\"Rust\"\"\""],
+            search_field("\"Rust\"", contents, 2, ',', true)
+        );
+        assert_eq!(
+            "\"started, rust\",INFO",
+            select_columns("INFO,\"started, rust\"", &[2, 1], ',')
+        );
+    }
+
+    #[test]
+    fn config_precedence() {
+        let defaults = "\
+# team defaults
+--ignore-case
+";
+        let config = Config::parse(args(&["to", "poem.txt"]), Some(defaults), false).unwrap();
+        assert!(!config.case_sensitive);
+
+        let config = Config::parse(args(&["-s", "to", "poem.txt"]), Some(defaults), false).unwrap();
+        assert!(config.case_sensitive);
+
+        let config = Config::parse(args(&["to", "poem.txt"]), Some("-s"), true).unwrap();
+        assert!(!config.case_sensitive);
+
+        let config = Config::parse(
+            args(&["--field=2", "-c", "1,2", "to", "log.tsv"]),
+            Some("--delimiter \\t"),
+            false,
+        )
+        .unwrap();
+        assert_eq!(Some(2), config.field);
+        assert_eq!('\t', config.delimiter);
+        assert_eq!(vec![1, 2], config.columns);
+
+        let config = Config::parse(args(&["--no-config", "to", "poem.txt"]), None, false).unwrap();
+        assert!(config.no_config);
+        let config = Config::parse(args(&["--", "--no-config", "poem.txt"]), None, false).unwrap();
+        assert!(!config.no_config);
+        assert_eq!("--no-config", config.query);
+    }
+
+    #[test]
+    fn config_errors() {
+        assert_eq!(
+            Err(String::from("Unknown option: --color")),
+            Config::parse(args(&["--color", "to", "poem.txt"]), None, false).map(|_| ())
+        );
+        // Only the command line rejects unknown options, the config file skips them
+        let config = Config::parse(
+            args(&["to", "poem.txt"]),
+            Some("--color always\n--ignore-case"),
+            false,
+        )
+        .unwrap();
+        assert!(!config.case_sensitive);
+        assert_eq!(
+            Err(String::from("Unexpected argument: extra")),
+            Config::parse(args(&["to", "poem.txt", "extra"]), None, false).map(|_| ())
+        );
+        assert_eq!(
+            Err(String::from("Column numbers start at 1")),
+            Config::parse(args(&["--field", "0", "to", "poem.txt"]), None, false).map(|_| ())
+        );
+        assert_eq!(
+            Err(String::from("Option --ignore-case doesn't take a value")),
+            Config::parse(args(&["--ignore-case=no", "to", "poem.txt"]), None, false).map(|_| ())
+        );
+    }
 }
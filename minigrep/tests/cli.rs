@@ -0,0 +1,250 @@
+//! Runs the `minigrep` binary against fixture trees and compares the result with golden files.
+//!
+//! Each golden file under `tests/golden` records the exit code, stdout and stderr of one case.
+//! Run with `UPDATE_GOLDEN=1` to rewrite them after an intentional change of the output.
+//!
+//! The messages of I/O errors differ between platforms, so they're recorded as placeholders.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const POEM: &str = "\
+I'm nobody! Who are you?
+Are you nobody, too?
+Then there's a pair of us - don't tell!
+They'd banish us, you know.
+
+How dreary to be somebody!
+How public, like a frog
+To tell your name the livelong day
+To an admiring bog!
+";
+
+const LOG: &str = "\
+level,message
+INFO,\"frog started, listening\"
+WARN,\"a \"\"Frog\"\" was seen\"
+frog,unquoted
+";
+
+/// A temporary directory tree that is removed when dropped.
+struct Fixture {
+    root: PathBuf,
+}
+
+impl Fixture {
+    fn new(files: &[(&str, &str)]) -> Fixture {
+        static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+        let root = env::temp_dir().join(format!(
+            "minigrep-test-{}-{}",
+            std::process::id(),
+            NEXT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        for (path, contents) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        fs::create_dir_all(&root).unwrap();
+        Fixture { root }
+    }
+
+    fn standard() -> Fixture {
+        Fixture::new(&[
+            ("poem.txt", POEM),
+            ("logs/app.csv", LOG),
+            ("conf/ignore-case", "# shared defaults\n--ignore-case\n"),
+            ("conf/fields", "--delimiter ,\n--field 2\n"),
+            ("conf/bad", "--color always\n--field zero\n"),
+        ])
+    }
+
+    /// Runs the binary inside the fixture with a clean environment plus `envs`.
+    fn run(&self, args: &[&str], envs: &[(&str, &str)]) -> String {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_minigrep"));
+        command
+            .args(args)
+            .current_dir(&self.root)
+            .env_remove("MINIGREP_CONFIG")
+            .env_remove("CASE_INSENSITIVE");
+        for (key, value) in envs {
+            command.env(key, value);
+        }
+        let output = command.output().unwrap();
+        let mut result = format!(
+            "exit: {}\n--- stdout\n{}--- stderr\n{}",
+            output
+                .status
+                .code()
+                .map_or(String::from("signal"), |code| code.to_string()),
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        for (error, placeholder) in self.os_errors() {
+            result = result.replace(&error, placeholder);
+        }
+        result
+    }
+
+    /// The messages of the errors from reading a missing file and a directory on this platform.
+    fn os_errors(&self) -> [(String, &'static str); 2] {
+        let error = |path: PathBuf| fs::read_to_string(path).unwrap_err().to_string();
+        [
+            (error(self.root.join("missing")), "<not found>"),
+            (error(self.root.clone()), "<is a directory>"),
+        ]
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+fn check_golden(name: &str, actual: &str) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.txt", name));
+    if env::var("UPDATE_GOLDEN").is_ok() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, actual).unwrap();
+        return;
+    }
+    let expected = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("Failed to read {}: {}", path.display(), e));
+    assert_eq!(expected, actual, "output differs from {}", path.display());
+}
+
+fn check(name: &str, args: &[&str], envs: &[(&str, &str)]) {
+    let fixture = Fixture::standard();
+    check_golden(name, &fixture.run(args, envs));
+}
+
+#[test]
+fn search_case_sensitive() {
+    check("search_case_sensitive", &["How", "poem.txt"], &[]);
+}
+
+#[test]
+fn search_ignore_case_flag() {
+    check("search_ignore_case_flag", &["-i", "how", "poem.txt"], &[]);
+}
+
+#[test]
+fn search_case_insensitive_env() {
+    check(
+        "search_case_insensitive_env",
+        &["how", "poem.txt"],
+        &[("CASE_INSENSITIVE", "1")],
+    );
+}
+
+#[test]
+fn query_after_double_dash() {
+    check(
+        "query_after_double_dash",
+        &["--", "- don't", "poem.txt"],
+        &[],
+    );
+}
+
+#[test]
+fn config_file_defaults() {
+    check(
+        "config_file_defaults",
+        &["how", "poem.txt"],
+        &[("MINIGREP_CONFIG", "conf/ignore-case")],
+    );
+}
+
+#[test]
+fn config_file_overridden_by_cli() {
+    check(
+        "config_file_overridden_by_cli",
+        &["--case-sensitive", "how", "poem.txt"],
+        &[("MINIGREP_CONFIG", "conf/ignore-case")],
+    );
+}
+
+#[test]
+fn no_config() {
+    check(
+        "no_config",
+        &["--no-config", "how", "poem.txt"],
+        &[("MINIGREP_CONFIG", "conf/ignore-case")],
+    );
+}
+
+#[test]
+fn field_search() {
+    check(
+        "field_search",
+        &["-i", "--field", "2", "frog", "logs/app.csv"],
+        &[],
+    );
+}
+
+#[test]
+fn field_search_from_config_with_columns() {
+    check(
+        "field_search_from_config_with_columns",
+        &["--columns=2,1", "frog", "logs/app.csv"],
+        &[("MINIGREP_CONFIG", "conf/fields")],
+    );
+}
+
+#[test]
+fn missing_query() {
+    check("missing_query", &[], &[]);
+}
+
+#[test]
+fn missing_file_name() {
+    check("missing_file_name", &["frog"], &[]);
+}
+
+#[test]
+fn unknown_option() {
+    check("unknown_option", &["--color", "frog", "poem.txt"], &[]);
+}
+
+#[test]
+fn missing_option_value() {
+    check(
+        "missing_option_value",
+        &["frog", "poem.txt", "--field"],
+        &[],
+    );
+}
+
+#[test]
+fn bad_config_file() {
+    check(
+        "bad_config_file",
+        &["frog", "poem.txt"],
+        &[("MINIGREP_CONFIG", "conf/bad")],
+    );
+}
+
+#[test]
+fn missing_config_file() {
+    check(
+        "missing_config_file",
+        &["frog", "poem.txt"],
+        &[("MINIGREP_CONFIG", "conf/missing")],
+    );
+}
+
+#[test]
+fn missing_input_file() {
+    check("missing_input_file", &["frog", "missing.txt"], &[]);
+}
+
+#[test]
+fn input_is_directory() {
+    check("input_is_directory", &["frog", "logs"], &[]);
+}
@@ -0,0 +1,72 @@
+// Copyright 2025 Yunze Xu
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compares the latency of fast jobs mixed with slow ones under each `Scheduling` mode.
+//!
+//! Run with `cargo run --release --example scheduling -p web-server`.
+
+use std::{
+    sync::mpsc::channel,
+    thread,
+    time::{Duration, Instant},
+};
+use web_server::thread_pool::{Scheduling, ThreadPool};
+
+const NUM_WORKERS: usize = 4;
+const NUM_JOBS: usize = 400;
+// Every 10th job is slow, like the `/sleep` route among normal requests
+const SLOW_EVERY: usize = 10;
+const SLOW_JOB: Duration = Duration::from_millis(50);
+const FAST_JOB: Duration = Duration::from_millis(1);
+const INTERVAL: Duration = Duration::from_millis(2);
+
+fn main() {
+    println!("mode        p50       p90       p99       max");
+    for scheduling in [Scheduling::RoundRobin, Scheduling::Shared] {
+        let mut latencies = run(scheduling);
+        latencies.sort();
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        println!(
+            "{:<10}  {:<8.1?}  {:<8.1?}  {:<8.1?}  {:.1?}",
+            format!("{:?}", scheduling),
+            percentile(50),
+            percentile(90),
+            percentile(99),
+            latencies.last().unwrap()
+        );
+    }
+}
+
+/// Returns the latency from submission to completion of every fast job.
+fn run(scheduling: Scheduling) -> Vec<Duration> {
+    let mut pool = ThreadPool::with_scheduling(NUM_WORKERS, scheduling);
+    let (sender, receiver) = channel();
+    for i in 0..NUM_JOBS {
+        let sender = sender.clone();
+        let submitted = Instant::now();
+        if i % SLOW_EVERY == 0 {
+            pool.execute(|| thread::sleep(SLOW_JOB));
+        } else {
+            pool.execute(move || {
+                thread::sleep(FAST_JOB);
+                sender.send(submitted.elapsed()).unwrap();
+            });
+        }
+        thread::sleep(INTERVAL);
+    }
+    drop(sender);
+    let latencies = receiver.iter().collect();
+    pool.shutdown();
+    latencies
+}
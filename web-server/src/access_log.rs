@@ -0,0 +1,505 @@
+// Copyright 2025 Yunze Xu
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    log,
+    middleware::{Middleware, RequestId},
+    request::Request,
+    response::{Body, Response},
+};
+use chrono::{DateTime, Local, SecondsFormat};
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The format of the lines of an [`AccessLog`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    /// The Common Log Format of Apache and nginx, e.g.
+    /// `127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] "GET /a.gif HTTP/1.0" 200 2326`.
+    #[default]
+    Common,
+    /// The Common Log Format followed by the quoted `Referer` and `User-Agent`.
+    Combined,
+    /// One JSON object per line, which also has the duration and the request ID.
+    Json,
+}
+
+/// Writes a line for every request, see [`LogFormat`].
+///
+/// The line is written once the size of the body is known: right after the handler for an
+/// in-memory body or a `Content-Length`, otherwise after the body is streamed, so that the
+/// duration includes the streaming. Add it before middleware that changes the body (e.g.
+/// [`Compression`](crate::compression::Compression)) to log the bytes actually sent.
+///
+/// As middleware, it only sees the requests that reach the router. The responses that the server
+/// makes up itself aren't logged here, but only in the server log: the errors for requests that
+/// can't be parsed or read in time (e.g. `400`, `408`, `413` and `431`), and the `503` for
+/// connections rejected when the thread pool is full.
+pub struct AccessLog {
+    format: LogFormat,
+    output: Arc<Mutex<Output>>,
+}
+
+enum Output {
+    Stdout,
+    File(RotatingFile),
+}
+
+impl Output {
+    fn write_line(&mut self, line: &str) {
+        let result = match self {
+            Output::Stdout => io::stdout().lock().write_all(line.as_bytes()),
+            Output::File(file) => file.write_line(line),
+        };
+        if let Err(e) = result {
+            log!("Failed to write the access log: {}", e);
+        }
+    }
+}
+
+impl Default for AccessLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccessLog {
+    /// Writes the Common Log Format to stdout.
+    pub fn new() -> Self {
+        AccessLog {
+            format: LogFormat::default(),
+            output: Arc::new(Mutex::new(Output::Stdout)),
+        }
+    }
+
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Writes to a file instead of stdout.
+    pub fn with_file(mut self, file: RotatingFile) -> Self {
+        self.output = Arc::new(Mutex::new(Output::File(file)));
+        self
+    }
+}
+
+struct Started {
+    instant: Instant,
+    time: DateTime<Local>,
+}
+
+impl Middleware for AccessLog {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        request.extensions.insert(Started {
+            instant: Instant::now(),
+            time: Local::now(),
+        });
+        None
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        let (start, time) = match request.extensions.get::<Started>() {
+            Some(started) => (started.instant, started.time),
+            None => (Instant::now(), Local::now()),
+        };
+        let entry = Entry {
+            time,
+            remote_addr: request.remote_addr,
+            method: request.method.clone(),
+            path: request.path.clone(),
+            query: request.query.clone(),
+            version: request.version.clone(),
+            status: response.status,
+            bytes: 0,
+            duration: Duration::ZERO,
+            referer: request.header("Referer").map(str::to_string),
+            user_agent: request.header("User-Agent").map(str::to_string),
+            request_id: RequestId::of(request).map(str::to_string),
+        };
+        let mut pending = Pending {
+            format: self.format,
+            output: self.output.clone(),
+            start,
+            entry,
+        };
+        let length = response
+            .header("Content-Length")
+            .and_then(|length| length.parse().ok());
+        match (&response.body, length) {
+            // The server drops the body
+            _ if request.method == "HEAD" => {}
+            (Body::Bytes(bytes), _) => pending.entry.bytes = bytes.len() as u64,
+            (Body::Stream(_), Some(length)) => pending.entry.bytes = length,
+            (Body::Stream(_), None) => {
+                let Body::Stream(stream) = std::mem::take(&mut response.body) else {
+                    unreachable!()
+                };
+                // The entry is written when the stream is done, or dropped if sending it fails
+                response.body = Body::Stream(Box::new(move |writer| {
+                    let mut pending = pending;
+                    let mut counter = Counter {
+                        inner: writer,
+                        bytes: &mut pending.entry.bytes,
+                    };
+                    stream(&mut counter)
+                }));
+            }
+        }
+    }
+}
+
+/// Counts the bytes written through it.
+struct Counter<'a> {
+    inner: &'a mut dyn Write,
+    bytes: &'a mut u64,
+}
+
+impl Write for Counter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        *self.bytes += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// An entry that's written when dropped.
+struct Pending {
+    format: LogFormat,
+    output: Arc<Mutex<Output>>,
+    start: Instant,
+    entry: Entry,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.entry.duration = self.start.elapsed();
+        let line = self.entry.format(self.format);
+        self.output.lock().unwrap().write_line(&line);
+    }
+}
+
+/// What's logged about a request.
+struct Entry {
+    time: DateTime<Local>,
+    remote_addr: Option<SocketAddr>,
+    method: String,
+    path: String,
+    query: Option<String>,
+    version: String,
+    status: u16,
+    bytes: u64,
+    duration: Duration,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+}
+
+impl Entry {
+    /// Formats the entry as a line that ends with `\n`.
+    fn format(&self, format: LogFormat) -> String {
+        let host = self
+            .remote_addr
+            .map_or("-".to_string(), |addr| addr.ip().to_string());
+        let target = match &self.query {
+            Some(query) => format!("{}?{}", self.path, query),
+            None => self.path.clone(),
+        };
+        let mut line = String::new();
+        match format {
+            LogFormat::Common | LogFormat::Combined => {
+                let _ = write!(
+                    line,
+                    "{} - - [{}] \"{} {} {}\" {} {}",
+                    host,
+                    self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+                    escape(&self.method),
+                    escape(&target),
+                    escape(&self.version),
+                    self.status,
+                    // `-` means no body, like `%b` of Apache
+                    match self.bytes {
+                        0 => "-".to_string(),
+                        bytes => bytes.to_string(),
+                    }
+                );
+                if format == LogFormat::Combined {
+                    let quoted =
+                        |value: &Option<String>| value.as_deref().map_or("-".to_string(), escape);
+                    let _ = write!(
+                        line,
+                        " \"{}\" \"{}\"",
+                        quoted(&self.referer),
+                        quoted(&self.user_agent)
+                    );
+                }
+            }
+            LogFormat::Json => {
+                let string = |value: &Option<String>| {
+                    value.as_deref().map_or("null".to_string(), json_string)
+                };
+                let _ = write!(
+                    line,
+                    "{{\"time\":{},\"remote_addr\":{},\"method\":{},\"path\":{},\"query\":{},\
+                     \"version\":{},\"status\":{},\"bytes\":{},\"duration_ms\":{:.3},\
+                     \"referer\":{},\"user_agent\":{},\"request_id\":{}}}",
+                    json_string(&self.time.to_rfc3339_opts(SecondsFormat::Millis, false)),
+                    string(&self.remote_addr.map(|addr| addr.ip().to_string())),
+                    json_string(&self.method),
+                    json_string(&self.path),
+                    string(&self.query),
+                    json_string(&self.version),
+                    self.status,
+                    self.bytes,
+                    self.duration.as_secs_f64() * 1000.0,
+                    string(&self.referer),
+                    string(&self.user_agent),
+                    string(&self.request_id)
+                );
+            }
+        }
+        line.push('\n');
+        line
+    }
+}
+
+/// Escapes a value for a quoted field of the Common Log Format, so that a client can't forge
+/// log lines or break the quoting.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => {
+                let _ = write!(escaped, "\\x{:02x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// A log file that's rotated when it would exceed a size: `access.log` is renamed to
+/// `access.log.1`, the previous `access.log.1` to `access.log.2` and so on, keeping at most
+/// `max_files` old files.
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    /// Opens `path` for appending, creating it if missing.
+    pub fn open<P: AsRef<Path>>(path: P, max_size: u64, max_files: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    /// Writes a whole line, rotating first if it doesn't fit. A line longer than `max_size` gets
+    /// a file of its own.
+    pub fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        // A single write so that the lines are never interleaved with other writers
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |i: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", i));
+            PathBuf::from(path)
+        };
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.max_files).rev() {
+                match fs::rename(rotated(i), rotated(i + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, rotated(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{router::Router, static_files::TempDir};
+    use chrono::TimeZone;
+
+    fn entry() -> Entry {
+        Entry {
+            time: Local.with_ymd_and_hms(2000, 10, 10, 13, 55, 36).unwrap(),
+            remote_addr: Some("127.0.0.1:50000".parse().unwrap()),
+            method: "GET".to_string(),
+            path: "/a \"b\".gif".to_string(),
+            query: Some("x=1".to_string()),
+            version: "HTTP/1.1".to_string(),
+            status: 200,
+            bytes: 2326,
+            duration: Duration::from_micros(1500),
+            referer: None,
+            user_agent: Some("curl/8.0\n".to_string()),
+            request_id: Some("1".to_string()),
+        }
+    }
+
+    #[test]
+    fn format_entries() {
+        let entry = entry();
+        let time = entry.time.format("%d/%b/%Y:%H:%M:%S %z").to_string();
+        assert_eq!(
+            format!(
+                "127.0.0.1 - - [{}] \"GET /a \\\"b\\\".gif?x=1 HTTP/1.1\" 200 2326\n",
+                time
+            ),
+            entry.format(LogFormat::Common)
+        );
+        assert_eq!(
+            format!(
+                "127.0.0.1 - - [{}] \"GET /a \\\"b\\\".gif?x=1 HTTP/1.1\" 200 2326 \"-\" \
+                 \"curl/8.0\\x0a\"\n",
+                time
+            ),
+            entry.format(LogFormat::Combined)
+        );
+        assert_eq!(
+            format!(
+                "{{\"time\":\"{}\",\"remote_addr\":\"127.0.0.1\",\"method\":\"GET\",\
+                 \"path\":\"/a \\\"b\\\".gif\",\"query\":\"x=1\",\"version\":\"HTTP/1.1\",\
+                 \"status\":200,\"bytes\":2326,\"duration_ms\":1.500,\"referer\":null,\
+                 \"user_agent\":\"curl/8.0\\n\",\"request_id\":\"1\"}}\n",
+                entry.time.to_rfc3339_opts(SecondsFormat::Millis, false)
+            ),
+            entry.format(LogFormat::Json)
+        );
+    }
+
+    #[test]
+    fn rotate_files() {
+        let dir = TempDir::new("rotate");
+        let path = dir.0.join("access.log");
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in [
+            "1111\n", "2222\n", "3333\n", "4444\n", "5555\n", "6666\n", "7\n",
+        ] {
+            file.write_line(line).unwrap();
+        }
+        let read = |name: &str| fs::read_to_string(dir.0.join(name)).unwrap();
+        assert_eq!("7\n", read("access.log"));
+        assert_eq!("5555\n6666\n", read("access.log.1"));
+        assert_eq!("3333\n4444\n", read("access.log.2"));
+        assert!(!dir.0.join("access.log.3").exists());
+
+        // Reopening appends to the current file
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        file.write_line("8\n").unwrap();
+        assert_eq!("7\n8\n", read("access.log"));
+    }
+
+    #[test]
+    fn log_requests() {
+        let dir = TempDir::new("access-log");
+        let path = dir.0.join("access.log");
+        let mut router = Router::new();
+        router
+            .wrap(
+                AccessLog::new()
+                    .with_format(LogFormat::Json)
+                    .with_file(RotatingFile::open(&path, 1 << 20, 1).unwrap()),
+            )
+            .get("/bytes", |_| Response::new(200).with_body("hello"))
+            .get("/stream", |_| {
+                Response::new(200).with_stream(|writer| writer.write_all(b"hello, world"))
+            });
+        let handle = |raw: &str| {
+            let mut request = Request::parse(&mut raw.as_bytes()).unwrap().unwrap();
+            request.remote_addr = Some("10.0.0.1:1234".parse().unwrap());
+            router.handle(&mut request)
+        };
+
+        handle("GET /bytes HTTP/1.1\r\nUser-Agent: test\r\n\r\n");
+        let response = handle("GET /stream HTTP/1.1\r\n\r\n");
+        // Not written until the body is streamed
+        assert_eq!(1, fs::read_to_string(&path).unwrap().lines().count());
+        response.body.into_bytes().unwrap();
+        handle("GET /missing HTTP/1.1\r\n\r\n");
+
+        let log = fs::read_to_string(&path).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(3, lines.len());
+        assert!(lines[0].contains("\"remote_addr\":\"10.0.0.1\""));
+        assert!(lines[0].contains("\"path\":\"/bytes\",\"query\":null"));
+        assert!(lines[0].contains("\"status\":200,\"bytes\":5,"));
+        assert!(lines[0].contains("\"user_agent\":\"test\""));
+        assert!(lines[1].contains("\"status\":200,\"bytes\":12,"));
+        assert!(lines[2].contains("\"status\":404,\"bytes\":0,"));
+    }
+}
@@ -0,0 +1,434 @@
+// Copyright 2025 Yunze Xu
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    log,
+    middleware::Middleware,
+    request::Request,
+    response::{Body, Response},
+};
+use flate2::write::{GzEncoder, ZlibEncoder};
+use std::{
+    io::{self, Write},
+    mem,
+};
+
+/// A content coding of a response body, see RFC 9110 8.4.1.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+    /// The zlib format, which is what `deflate` means in HTTP.
+    Deflate,
+}
+
+impl Encoding {
+    /// The token used in `Accept-Encoding` and `Content-Encoding`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+
+    fn encoder(self, writer: &mut dyn Write) -> Encoder<'_> {
+        match self {
+            // Quality 5 compresses about as well as gzip -9 while staying fast enough for
+            // dynamic responses, the default 11 is meant for precompressing files
+            Encoding::Brotli => {
+                Encoder::Brotli(Box::new(brotli::CompressorWriter::new(writer, 4096, 5, 22)))
+            }
+            Encoding::Gzip => Encoder::Gzip(GzEncoder::new(writer, flate2::Compression::default())),
+            Encoding::Deflate => {
+                Encoder::Deflate(ZlibEncoder::new(writer, flate2::Compression::default()))
+            }
+        }
+    }
+}
+
+/// Compresses what's written to it into the underlying writer.
+enum Encoder<'a> {
+    // Boxed since its state is much larger than the others
+    Brotli(Box<brotli::CompressorWriter<&'a mut dyn Write>>),
+    Gzip(GzEncoder<&'a mut dyn Write>),
+    Deflate(ZlibEncoder<&'a mut dyn Write>),
+}
+
+impl Encoder<'_> {
+    /// Writes the end of the compressed stream.
+    fn finish(self) -> io::Result<()> {
+        match self {
+            Encoder::Brotli(mut encoder) => {
+                // `into_inner` ignores errors, though a broken writer fails the flush already
+                encoder.flush()?;
+                encoder.into_inner();
+                Ok(())
+            }
+            Encoder::Gzip(encoder) => encoder.finish().map(drop),
+            Encoder::Deflate(encoder) => encoder.finish().map(drop),
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Brotli(encoder) => encoder,
+            Encoder::Gzip(encoder) => encoder,
+            Encoder::Deflate(encoder) => encoder,
+        }
+    }
+}
+
+impl Write for Encoder<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
+
+/// Chooses the encoding in `available` that the client prefers by `Accept-Encoding`, ties are
+/// broken by the order of `available`. Returns `None` if the body should be sent as is.
+///
+/// An encoding is acceptable if it's listed, or matched by `*`, with a non-zero `q` value.
+pub fn negotiate(request: &Request, available: &[Encoding]) -> Option<Encoding> {
+    let accept = request.header("Accept-Encoding")?;
+    let mut qualities = vec![];
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        if name.is_empty() {
+            continue;
+        }
+        let quality = params
+            .find_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("q")
+                    .then(|| value.trim().parse::<f32>().unwrap_or(0.0))
+            })
+            .unwrap_or(1.0);
+        qualities.push((name, quality));
+    }
+    let quality = |name: &str| {
+        let find = |name: &str| qualities.iter().find(|(key, _)| key == name);
+        find(name)
+            // `x-gzip` is an alias kept for compatibility, see RFC 9110 8.4.1.3
+            .or_else(|| (name == "gzip").then(|| find("x-gzip")).flatten())
+            .or_else(|| find("*"))
+            .map_or(0.0, |(_, quality)| *quality)
+    };
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in available {
+        let quality = quality(encoding.name());
+        if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Adds `header` to the `Vary` of the response unless it's already there.
+pub fn add_vary(response: &mut Response, header: &str) {
+    let vary = match response.header("Vary") {
+        Some(vary)
+            if vary
+                .split(',')
+                .any(|name| name.trim() == "*" || name.trim().eq_ignore_ascii_case(header)) =>
+        {
+            return;
+        }
+        Some(vary) => format!("{}, {}", vary, header),
+        None => header.to_string(),
+    };
+    response.set_header("Vary", vary);
+}
+
+/// Compresses the responses whose clients accept it as [`Middleware`], so wrap a router with it
+/// to compress all of its responses.
+///
+/// Only successful responses with a text-like `Content-Type` and a body of at least
+/// [`Compression::with_min_size`] bytes are compressed, since images, archives and the like are
+/// compressed already and small bodies aren't worth it. A streamed body without a
+/// `Content-Length` is compressed on the fly.
+#[derive(Clone, Debug)]
+pub struct Compression {
+    encodings: Vec<Encoding>,
+    min_size: u64,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            encodings: vec![Encoding::Brotli, Encoding::Gzip, Encoding::Deflate],
+            min_size: 1024,
+        }
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the encodings to use in order of preference, brotli, gzip and deflate by default.
+    pub fn with_encodings(mut self, encodings: &[Encoding]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// Sets the smallest body to compress, 1024 bytes by default.
+    pub fn with_min_size(mut self, min_size: u64) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Compresses the response to `request` with the encoding negotiated by `Accept-Encoding`.
+    ///
+    /// A compressible response gets `Vary: Accept-Encoding` even if it's sent as is, so that
+    /// caches don't serve either version to the wrong client. The `ETag` becomes weak because
+    /// the compressed bytes depend on the encoder.
+    pub fn compress(&self, request: &Request, mut response: Response) -> Response {
+        if !self.is_compressible(&response) {
+            return response;
+        }
+        add_vary(&mut response, "Accept-Encoding");
+        let Some(encoding) = negotiate(request, &self.encodings) else {
+            return response;
+        };
+        response.body = match mem::take(&mut response.body) {
+            Body::Bytes(bytes) => match encode(encoding, &bytes) {
+                Ok(compressed) => Body::Bytes(compressed),
+                Err(e) => {
+                    log!("Failed to compress the response with {:?}: {}", encoding, e);
+                    response.body = Body::Bytes(bytes);
+                    return response;
+                }
+            },
+            Body::Stream(stream) => Body::Stream(Box::new(move |writer| {
+                let mut encoder = encoding.encoder(writer);
+                stream(&mut encoder)?;
+                encoder.finish()
+            })),
+        };
+        // The ranges of the encoded body can't be served, since it's encoded on the fly
+        response.headers.retain(|(name, _)| {
+            !name.eq_ignore_ascii_case("Content-Length")
+                && !name.eq_ignore_ascii_case("Accept-Ranges")
+        });
+        response.set_header("Content-Encoding", encoding.name());
+        if let Some(etag) = response.header("ETag").filter(|etag| etag.starts_with('"')) {
+            response.set_header("ETag", format!("W/{}", etag));
+        }
+        response
+    }
+
+    fn is_compressible(&self, response: &Response) -> bool {
+        if !(200..300).contains(&response.status)
+            || matches!(response.status, 204 | 206)
+            || response.header("Content-Encoding").is_some()
+            || response.header("Content-Range").is_some()
+            // Proxies and servers must keep the body as is, see RFC 9111 5.2.2.6
+            || response
+                .header("Cache-Control")
+                .is_some_and(|value| value.to_ascii_lowercase().contains("no-transform"))
+            || !response.header("Content-Type").is_some_and(is_text_like)
+        {
+            return false;
+        }
+        let size = match &response.body {
+            Body::Bytes(bytes) => Some(bytes.len() as u64),
+            Body::Stream(_) => response
+                .header("Content-Length")
+                .and_then(|length| length.parse().ok()),
+        };
+        size.is_none_or(|size| size >= self.min_size)
+    }
+}
+
+impl Middleware for Compression {
+    fn after(&self, request: &Request, response: &mut Response) {
+        let uncompressed = mem::replace(response, Response::new(response.status));
+        *response = self.compress(request, uncompressed);
+    }
+}
+
+fn encode(encoding: Encoding, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let mut compressed = vec![];
+    let mut encoder = encoding.encoder(&mut compressed);
+    encoder.write_all(bytes)?;
+    encoder.finish()?;
+    Ok(compressed)
+}
+
+/// Whether a body of `content_type` is worth compressing.
+fn is_text_like(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "image/x-icon"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::{GzDecoder, ZlibDecoder};
+    use std::io::Read;
+
+    fn request(accept_encoding: &str) -> Request {
+        let raw = format!(
+            "GET / HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+            accept_encoding
+        );
+        Request::parse(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn decode(encoding: Encoding, compressed: &[u8]) -> String {
+        let mut decoded = String::new();
+        match encoding {
+            Encoding::Brotli => brotli::Decompressor::new(compressed, 4096)
+                .read_to_string(&mut decoded)
+                .unwrap(),
+            Encoding::Gzip => GzDecoder::new(compressed)
+                .read_to_string(&mut decoded)
+                .unwrap(),
+            Encoding::Deflate => ZlibDecoder::new(compressed)
+                .read_to_string(&mut decoded)
+                .unwrap(),
+        };
+        decoded
+    }
+
+    #[test]
+    fn negotiate_encoding() {
+        use Encoding::*;
+        let all = [Brotli, Gzip, Deflate];
+        assert_eq!(Some(Brotli), negotiate(&request("gzip, deflate, br"), &all));
+        assert_eq!(
+            Some(Gzip),
+            negotiate(&request("gzip, deflate, br"), &[Gzip, Brotli])
+        );
+        assert_eq!(Some(Gzip), negotiate(&request("br;q=0.5, gzip"), &all));
+        assert_eq!(
+            Some(Deflate),
+            negotiate(&request("br;q=0, gzip;q=0, *"), &all)
+        );
+        assert_eq!(Some(Gzip), negotiate(&request("x-gzip"), &all));
+        assert_eq!(None, negotiate(&request("identity"), &all));
+        assert_eq!(None, negotiate(&request("*;q=0"), &all));
+        assert_eq!(None, negotiate(&request("gzip"), &[Brotli]));
+        let raw = "GET / HTTP/1.1\r\n\r\n";
+        let request = Request::parse(&mut raw.as_bytes()).unwrap().unwrap();
+        assert_eq!(None, negotiate(&request, &all));
+    }
+
+    #[test]
+    fn compress_responses() {
+        let text = "hello, world\n".repeat(100);
+        let response = || {
+            Response::new(200)
+                .with_header("Content-Type", "text/plain; charset=utf-8")
+                .with_header("ETag", "\"1\"")
+                .with_body(text.as_str())
+        };
+        let compression = Compression::new();
+
+        for (accept, encoding) in [
+            ("br", Encoding::Brotli),
+            ("gzip", Encoding::Gzip),
+            ("deflate", Encoding::Deflate),
+        ] {
+            let compressed = compression.compress(&request(accept), response());
+            assert_eq!(Some(encoding.name()), compressed.header("Content-Encoding"));
+            assert_eq!(Some("Accept-Encoding"), compressed.header("Vary"));
+            assert_eq!(Some("W/\"1\""), compressed.header("ETag"));
+            let body = compressed.body.as_bytes().unwrap();
+            assert!(body.len() < text.len());
+            assert_eq!(text, decode(encoding, body));
+        }
+
+        let streamed_text = text.clone();
+        let streamed = Response::new(200)
+            .with_header("Content-Type", "text/plain")
+            .with_header("Content-Length", text.len().to_string())
+            .with_header("Accept-Ranges", "bytes")
+            .with_header("Vary", "Origin")
+            .with_stream(move |writer| writer.write_all(streamed_text.as_bytes()));
+        let compressed = compression.compress(&request("gzip"), streamed);
+        assert_eq!(Some("gzip"), compressed.header("Content-Encoding"));
+        assert_eq!(Some("Origin, Accept-Encoding"), compressed.header("Vary"));
+        assert_eq!(None, compressed.header("Content-Length"));
+        assert_eq!(None, compressed.header("Accept-Ranges"));
+        let body = compressed.body.into_bytes().unwrap();
+        assert_eq!(text, decode(Encoding::Gzip, &body));
+
+        // Not accepted by the client, but a cache must know the response could differ
+        let response = compression.compress(&request("identity"), response());
+        assert_eq!(None, response.header("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.header("Vary"));
+        assert_eq!(Some("\"1\""), response.header("ETag"));
+    }
+
+    #[test]
+    fn skip_incompressible_responses() {
+        let compression = Compression::new().with_min_size(10);
+        let skipped = |response: Response| {
+            let response = compression.compress(&request("gzip"), response);
+            response.header("Content-Encoding").is_none() && response.header("Vary").is_none()
+        };
+        let text = "x".repeat(100);
+        assert!(skipped(Response::new(200).with_body(text.as_str())));
+        assert!(skipped(
+            Response::new(200)
+                .with_header("Content-Type", "image/png")
+                .with_body(text.as_str())
+        ));
+        assert!(skipped(
+            Response::new(200)
+                .with_header("Content-Type", "text/plain")
+                .with_body("short")
+        ));
+        assert!(skipped(
+            Response::new(206)
+                .with_header("Content-Type", "text/plain")
+                .with_body(text.as_str())
+        ));
+        assert!(skipped(
+            Response::new(200)
+                .with_header("Content-Type", "text/plain")
+                .with_header("Cache-Control", "no-transform")
+                .with_body(text.as_str())
+        ));
+        assert!(!skipped(
+            Response::new(200)
+                .with_header("Content-Type", "application/ld+json")
+                .with_body(text.as_str())
+        ));
+    }
+}
@@ -0,0 +1,891 @@
+// Copyright 2025 Yunze Xu
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    access_log::{AccessLog, LogFormat, RotatingFile},
+    compression::Compression,
+    log,
+    metrics::Metrics,
+    middleware::{RequestId, SecurityHeaders, Timing},
+    response::Response,
+    router::Router,
+    server::{Reload, ServerConfig},
+    static_files::{self, StaticFiles},
+    thread_pool::{Builder, Scheduling, ThreadPool},
+    tls::TlsConfig,
+};
+use serde::{Deserialize, Deserializer, de::Error as _};
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+/// The settings of a server read from a TOML file, see `web-server.toml` for all of them.
+///
+/// Every section is optional. Durations are in seconds, and relative paths are resolved from the
+/// working directory. [`Config::parse`] checks the values, while the files are checked when the
+/// parts of the server are built, e.g. by [`Config::router`].
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSection,
+    pub tls: Option<TlsSection>,
+    pub static_files: Option<StaticFilesSection>,
+    pub thread_pool: ThreadPoolSection,
+    pub limits: LimitsSection,
+    pub logging: LoggingSection,
+    pub routes: Vec<RouteSection>,
+}
+
+/// The `[server]` section.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSection {
+    /// The address to listen on, `127.0.0.1:7878` by default.
+    pub listen: String,
+    /// The address of a plain HTTP listener that redirects to HTTPS, which requires `[tls]`.
+    pub https_redirect: Option<String>,
+    /// Whether to compress the responses, enabled by default.
+    pub compression: bool,
+    /// The path to serve the Prometheus metrics at, e.g. `/metrics`. They aren't collected if
+    /// it's not set.
+    pub metrics: Option<String>,
+    /// The page of `404 Not Found` responses.
+    pub not_found: Option<PathBuf>,
+}
+
+impl Default for ServerSection {
+    fn default() -> Self {
+        ServerSection {
+            listen: "127.0.0.1:7878".to_string(),
+            https_redirect: None,
+            compression: true,
+            metrics: None,
+            not_found: None,
+        }
+    }
+}
+
+/// The `[tls]` section, which serves HTTPS instead of plain HTTP.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsSection {
+    /// The PEM file of the default certificate chain.
+    pub cert: PathBuf,
+    /// The PEM file of the private key of `cert`.
+    pub key: PathBuf,
+    /// The certificates chosen by SNI, in `[[tls.sni]]` tables.
+    #[serde(default)]
+    pub sni: Vec<SniSection>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SniSection {
+    /// The host name like `example.com` or `*.example.com`.
+    pub host_name: String,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// The `[static_files]` section, which serves the files under a directory for the paths that
+/// match no route.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct StaticFilesSection {
+    pub root: PathBuf,
+    /// Whether to serve the `.br` and `.gz` files next to the requested ones.
+    #[serde(default)]
+    pub precompressed: bool,
+    /// The `Cache-Control` of the files, `no-cache` by default. An empty value omits it.
+    #[serde(default = "default_cache_control")]
+    pub cache_control: String,
+}
+
+fn default_cache_control() -> String {
+    "no-cache".to_string()
+}
+
+/// The `[thread_pool]` section, see [`Builder`].
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThreadPoolSection {
+    /// The number of workers, 4 by default.
+    pub workers: usize,
+    /// Either `round-robin` (the default) or `shared`.
+    #[serde(deserialize_with = "scheduling")]
+    pub scheduling: Scheduling,
+    pub queue_capacity: Option<usize>,
+    /// The number of workers to grow up to, which requires the `shared` scheduling.
+    pub max_workers: Option<usize>,
+    pub reserved_workers: usize,
+}
+
+impl Default for ThreadPoolSection {
+    fn default() -> Self {
+        ThreadPoolSection {
+            workers: 4,
+            scheduling: Scheduling::default(),
+            queue_capacity: None,
+            max_workers: None,
+            reserved_workers: 0,
+        }
+    }
+}
+
+/// The `[limits]` section, see [`ServerConfig`] for the defaults.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsSection {
+    pub keep_alive_timeout: f64,
+    pub header_read_timeout: f64,
+    pub body_read_timeout: f64,
+    pub write_timeout: f64,
+    pub shutdown_timeout: f64,
+    pub retry_after: f64,
+    pub max_header_size: usize,
+    pub max_headers: usize,
+    pub max_body_size: usize,
+}
+
+impl Default for LimitsSection {
+    fn default() -> Self {
+        let config = ServerConfig::default();
+        LimitsSection {
+            keep_alive_timeout: config.keep_alive_timeout.as_secs_f64(),
+            header_read_timeout: config.header_read_timeout.as_secs_f64(),
+            body_read_timeout: config.body_read_timeout.as_secs_f64(),
+            write_timeout: config.write_timeout.as_secs_f64(),
+            shutdown_timeout: config.shutdown_timeout.as_secs_f64(),
+            retry_after: config.retry_after.as_secs_f64(),
+            max_header_size: config.max_header_size,
+            max_headers: config.max_headers,
+            max_body_size: config.max_body_size,
+        }
+    }
+}
+
+/// The `[logging]` section of the access log.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSection {
+    /// Whether to write the access log, enabled by default.
+    pub access_log: bool,
+    /// Either `common` (the default), `combined` or `json`.
+    #[serde(deserialize_with = "log_format")]
+    pub format: LogFormat,
+    /// The file to write to instead of stdout, which is rotated when it would exceed `max_size`
+    /// bytes (10 MiB by default), keeping `max_files` old files (5 by default).
+    pub file: Option<PathBuf>,
+    pub max_size: u64,
+    pub max_files: usize,
+}
+
+impl Default for LoggingSection {
+    fn default() -> Self {
+        LoggingSection {
+            access_log: true,
+            format: LogFormat::default(),
+            file: None,
+            max_size: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+/// A `[[routes]]` table, which responds with either a file, a body or a redirect.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RouteSection {
+    /// `GET` by default.
+    #[serde(default = "default_method")]
+    pub method: String,
+    /// The pattern of the path, see [`Router`].
+    pub path: String,
+    /// A file read on every request, whose content type follows its extension.
+    pub file: Option<PathBuf>,
+    pub body: Option<String>,
+    /// The `Location` to redirect to.
+    pub redirect: Option<String>,
+    /// 200 by default, or 308 for a redirect.
+    pub status: Option<u16>,
+    /// The `Content-Type`, `text/plain; charset=utf-8` by default for a body.
+    pub content_type: Option<String>,
+    /// More response headers.
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// How long to wait before responding, e.g. to try out the thread pool.
+    #[serde(default)]
+    pub delay: f64,
+    /// Whether the requests skip the queue, see [`Router::high_priority`].
+    #[serde(default)]
+    pub high_priority: bool,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+fn scheduling<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Scheduling, D::Error> {
+    match String::deserialize(deserializer)?.as_str() {
+        "round-robin" => Ok(Scheduling::RoundRobin),
+        "shared" => Ok(Scheduling::Shared),
+        other => Err(D::Error::unknown_variant(other, &["round-robin", "shared"])),
+    }
+}
+
+fn log_format<'de, D: Deserializer<'de>>(deserializer: D) -> Result<LogFormat, D::Error> {
+    match String::deserialize(deserializer)?.as_str() {
+        "common" => Ok(LogFormat::Common),
+        "combined" => Ok(LogFormat::Combined),
+        "json" => Ok(LogFormat::Json),
+        other => Err(D::Error::unknown_variant(
+            other,
+            &["common", "combined", "json"],
+        )),
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file can't be read.
+    Io(io::Error),
+    /// The file isn't valid TOML, or has unknown keys or values of wrong types.
+    Parse(toml::de::Error),
+    /// The setting with the key has an invalid value.
+    Invalid(String, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "failed to read the config: {}", e),
+            ConfigError::Parse(e) => write!(f, "failed to parse the config: {}", e),
+            ConfigError::Invalid(key, message) => write!(f, "invalid {}: {}", key, message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(e) => Some(e),
+            ConfigError::Parse(e) => Some(e),
+            ConfigError::Invalid(..) => None,
+        }
+    }
+}
+
+fn invalid(key: &str, message: impl fmt::Display) -> ConfigError {
+    ConfigError::Invalid(key.to_string(), message.to_string())
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, ConfigError> {
+        let config: Config = toml::from_str(text).map_err(ConfigError::Parse)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let server = &self.server;
+        resolve("server.listen", &server.listen)?;
+        if let Some(addr) = &server.https_redirect {
+            if self.tls.is_none() {
+                return Err(invalid(
+                    "server.https_redirect",
+                    "requires the [tls] section",
+                ));
+            }
+            resolve("server.https_redirect", addr)?;
+        }
+        if let Some(path) = &server.metrics {
+            check_pattern("server.metrics", path)?;
+        }
+
+        let pool = &self.thread_pool;
+        if pool.workers == 0 {
+            return Err(invalid("thread_pool.workers", "must be positive"));
+        }
+        if pool.max_workers.is_some_and(|max| max < pool.workers) {
+            return Err(invalid(
+                "thread_pool.max_workers",
+                "must be at least thread_pool.workers",
+            ));
+        }
+        if pool.max_workers.is_some() && pool.scheduling != Scheduling::Shared {
+            return Err(invalid(
+                "thread_pool.max_workers",
+                "requires the \"shared\" scheduling",
+            ));
+        }
+        if pool.queue_capacity == Some(0) {
+            return Err(invalid("thread_pool.queue_capacity", "must be positive"));
+        }
+
+        let limits = &self.limits;
+        for (key, seconds) in [
+            ("limits.keep_alive_timeout", limits.keep_alive_timeout),
+            ("limits.header_read_timeout", limits.header_read_timeout),
+            ("limits.body_read_timeout", limits.body_read_timeout),
+            ("limits.write_timeout", limits.write_timeout),
+            ("limits.shutdown_timeout", limits.shutdown_timeout),
+            ("limits.retry_after", limits.retry_after),
+        ] {
+            if Duration::try_from_secs_f64(seconds).is_ok_and(|duration| !duration.is_zero()) {
+                continue;
+            }
+            return Err(invalid(key, "must be a positive number of seconds"));
+        }
+        if limits.max_header_size == 0 {
+            return Err(invalid("limits.max_header_size", "must be positive"));
+        }
+        if limits.max_headers == 0 {
+            return Err(invalid("limits.max_headers", "must be positive"));
+        }
+        if limits.max_body_size == 0 {
+            return Err(invalid("limits.max_body_size", "must be positive"));
+        }
+        if self.logging.max_size == 0 {
+            return Err(invalid("logging.max_size", "must be positive"));
+        }
+
+        for (i, route) in self.routes.iter().enumerate() {
+            route.validate(&format!("routes[{}]", i))?;
+        }
+        Ok(())
+    }
+
+    /// The [`ServerConfig`] of the `[limits]`.
+    pub fn server_config(&self) -> ServerConfig {
+        let limits = &self.limits;
+        ServerConfig {
+            keep_alive_timeout: Duration::from_secs_f64(limits.keep_alive_timeout),
+            header_read_timeout: Duration::from_secs_f64(limits.header_read_timeout),
+            body_read_timeout: Duration::from_secs_f64(limits.body_read_timeout),
+            write_timeout: Duration::from_secs_f64(limits.write_timeout),
+            max_header_size: limits.max_header_size,
+            max_headers: limits.max_headers,
+            max_body_size: limits.max_body_size,
+            shutdown_timeout: Duration::from_secs_f64(limits.shutdown_timeout),
+            retry_after: Duration::from_secs_f64(limits.retry_after),
+        }
+    }
+
+    pub fn thread_pool(&self) -> ThreadPool {
+        let section = &self.thread_pool;
+        let mut builder = Builder::new(section.workers)
+            .scheduling(section.scheduling)
+            .reserved_workers(section.reserved_workers);
+        if let Some(capacity) = section.queue_capacity {
+            builder = builder.queue_capacity(capacity);
+        }
+        if let Some(max_workers) = section.max_workers {
+            builder = builder.max_workers(max_workers);
+        }
+        builder.build()
+    }
+
+    /// Loads the certificates of the `[tls]` section, if any.
+    pub fn tls(&self) -> Result<Option<TlsConfig>, ConfigError> {
+        let Some(section) = &self.tls else {
+            return Ok(None);
+        };
+        let mut tls = TlsConfig::from_pem_files(&section.cert, &section.key)
+            .map_err(|e| invalid("tls", e))?;
+        for (i, sni) in section.sni.iter().enumerate() {
+            tls = tls
+                .with_sni_pem_files(&sni.host_name, &sni.cert, &sni.key)
+                .map_err(|e| invalid(&format!("tls.sni[{}]", i), e))?;
+        }
+        Ok(Some(tls))
+    }
+
+    /// Builds the router of the routes, the metrics and the static files in this order, wrapped
+    /// in the middleware of the `[server]` and `[logging]` sections. The metrics are collected
+    /// into `metrics`, which should be the same across reloads.
+    pub fn router(&self, metrics: &Metrics) -> Result<Router, ConfigError> {
+        let mut router = Router::new();
+        if self.server.metrics.is_some() {
+            router.wrap(metrics.clone());
+        }
+        router.wrap(Timing::new()).wrap(RequestId::new());
+        let logging = &self.logging;
+        if logging.access_log {
+            let mut access_log = AccessLog::new().with_format(logging.format);
+            if let Some(path) = &logging.file {
+                let file = RotatingFile::open(path, logging.max_size, logging.max_files)
+                    .map_err(|e| invalid("logging.file", format!("{}: {}", path.display(), e)))?;
+                access_log = access_log.with_file(file);
+            }
+            router.wrap(access_log);
+        }
+        router.wrap(SecurityHeaders::new());
+        if self.server.compression {
+            router.wrap(Compression::new());
+        }
+
+        for (i, route) in self.routes.iter().enumerate() {
+            if let Some(file) = &route.file {
+                check_file(&format!("routes[{}].file", i), file)?;
+            }
+            let delay = Duration::from_secs_f64(route.delay);
+            let section = route.clone();
+            router.route(&route.method, &route.path, move |_| {
+                if !delay.is_zero() {
+                    thread::sleep(delay);
+                }
+                section.respond()
+            });
+            if route.high_priority {
+                router.high_priority(&route.path);
+            }
+        }
+        if let Some(path) = &self.server.metrics {
+            router.get(path, metrics.handler()).high_priority(path);
+        }
+
+        let not_found = self.server.not_found.clone();
+        if let Some(file) = &not_found {
+            check_file("server.not_found", file)?;
+        }
+        let files = match &self.static_files {
+            Some(section) => {
+                let cache_control =
+                    Some(section.cache_control.as_str()).filter(|value| !value.is_empty());
+                let files = StaticFiles::new(&section.root)
+                    .map_err(|e| {
+                        invalid(
+                            "static_files.root",
+                            format!("{}: {}", section.root.display(), e),
+                        )
+                    })?
+                    .with_precompressed(section.precompressed)
+                    .with_cache_control(cache_control);
+                Some(files)
+            }
+            None => None,
+        };
+        // The static files only fill in for the paths without a route, so that other methods
+        // than GET are still answered with 404 rather than 405
+        router.not_found(move |request| {
+            let response = match &files {
+                Some(files) if matches!(request.method.as_str(), "GET" | "HEAD") => {
+                    files.serve(request)
+                }
+                _ => Response::new(404),
+            };
+            if response.status == 404 {
+                return not_found_page(not_found.as_deref());
+            }
+            response
+        });
+        Ok(router)
+    }
+
+    /// Returns the keys of the settings that differ in `new` but only change by a restart: the
+    /// listeners, whether HTTPS is served and the thread pool except the number of workers.
+    pub fn restart_required(&self, new: &Config) -> Vec<&'static str> {
+        let (server, new_server) = (&self.server, &new.server);
+        let (pool, new_pool) = (&self.thread_pool, &new.thread_pool);
+        [
+            ("server.listen", server.listen != new_server.listen),
+            (
+                "server.https_redirect",
+                server.https_redirect != new_server.https_redirect,
+            ),
+            ("tls", self.tls.is_some() != new.tls.is_some()),
+            (
+                "thread_pool.scheduling",
+                pool.scheduling != new_pool.scheduling,
+            ),
+            (
+                "thread_pool.queue_capacity",
+                pool.queue_capacity != new_pool.queue_capacity,
+            ),
+            (
+                "thread_pool.max_workers",
+                pool.max_workers != new_pool.max_workers,
+            ),
+            (
+                "thread_pool.reserved_workers",
+                pool.reserved_workers != new_pool.reserved_workers,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(key, changed)| changed.then_some(key))
+        .collect()
+    }
+
+    /// Builds the [`Reload`] that switches a server started with `running` to this config, where
+    /// `running` has the current number of workers so that the thread pool is only touched if it
+    /// differs. The settings that require a restart are logged and left out.
+    pub fn reload(&self, running: &Config, metrics: &Metrics) -> Result<Reload, ConfigError> {
+        for key in running.restart_required(self) {
+            log!("Restart the server to apply the change of {}", key);
+        }
+        let mut reload = Reload::new()
+            .with_router(self.router(metrics)?)
+            .with_config(self.server_config());
+        if let Some(tls) = self.tls()? {
+            reload = reload.with_tls(&tls);
+        }
+        if self.thread_pool.workers != running.thread_pool.workers {
+            reload = reload.with_workers(self.thread_pool.workers);
+        }
+        Ok(reload)
+    }
+}
+
+impl RouteSection {
+    fn validate(&self, key: &str) -> Result<(), ConfigError> {
+        if self.method.is_empty() || !self.method.bytes().all(|c| c.is_ascii_uppercase()) {
+            return Err(invalid(
+                &format!("{}.method", key),
+                "must be an upper-case method like GET",
+            ));
+        }
+        check_pattern(&format!("{}.path", key), &self.path)?;
+        let responses = [
+            self.file.is_some(),
+            self.body.is_some(),
+            self.redirect.is_some(),
+        ];
+        if responses.into_iter().filter(|&set| set).count() != 1 {
+            return Err(invalid(key, "needs exactly one of file, body and redirect"));
+        }
+        match self.status {
+            Some(status) if !(100..=599).contains(&status) => {
+                return Err(invalid(
+                    &format!("{}.status", key),
+                    "must be between 100 and 599",
+                ));
+            }
+            Some(status) if self.redirect.is_some() && !(300..=399).contains(&status) => {
+                return Err(invalid(
+                    &format!("{}.status", key),
+                    "must be a redirection (3xx) with redirect",
+                ));
+            }
+            _ => {}
+        }
+        if !(self.delay.is_finite() && self.delay >= 0.0) {
+            return Err(invalid(
+                &format!("{}.delay", key),
+                "must be a number of seconds",
+            ));
+        }
+        Ok(())
+    }
+
+    fn respond(&self) -> Response {
+        let mut response = if let Some(file) = &self.file {
+            file_response(self.status.unwrap_or(200), file)
+        } else if let Some(location) = &self.redirect {
+            Response::new(self.status.unwrap_or(308)).with_header("Location", location.as_str())
+        } else {
+            Response::new(self.status.unwrap_or(200))
+                .with_header("Content-Type", "text/plain; charset=utf-8")
+                .with_body(self.body.clone().unwrap_or_default())
+        };
+        if let Some(content_type) = &self.content_type {
+            response.set_header("Content-Type", content_type.as_str());
+        }
+        for (name, value) in &self.headers {
+            response.set_header(name, value.as_str());
+        }
+        response
+    }
+}
+
+fn resolve(key: &str, addr: &str) -> Result<(), ConfigError> {
+    match addr.to_socket_addrs() {
+        Ok(addrs) if addrs.len() > 0 => Ok(()),
+        Ok(_) => Err(invalid(key, format!("{} has no address", addr))),
+        Err(e) => Err(invalid(key, format!("{}: {}", addr, e))),
+    }
+}
+
+/// Checks the rules of [`Router::route`], which panics on invalid patterns.
+fn check_pattern(key: &str, pattern: &str) -> Result<(), ConfigError> {
+    let Some(rest) = pattern.strip_prefix('/') else {
+        return Err(invalid(key, "must start with '/'"));
+    };
+    let segments = rest.split('/').collect::<Vec<_>>();
+    if segments[..segments.len() - 1]
+        .iter()
+        .any(|segment| segment.starts_with('*'))
+    {
+        return Err(invalid(key, "can only have a wildcard as the last segment"));
+    }
+    Ok(())
+}
+
+fn check_file(key: &str, file: &Path) -> Result<(), ConfigError> {
+    match fs::metadata(file) {
+        Ok(metadata) if metadata.is_file() => Ok(()),
+        Ok(_) => Err(invalid(key, format!("{} is not a file", file.display()))),
+        Err(e) => Err(invalid(key, format!("{}: {}", file.display(), e))),
+    }
+}
+
+/// Responds with the content of `file`, which is read on every request so that it can be edited
+/// while the server runs.
+fn file_response(status: u16, file: &Path) -> Response {
+    match fs::read(file) {
+        Ok(content) => Response::new(status)
+            .with_header("Content-Type", static_files::content_type(file))
+            .with_body(content),
+        Err(e) => {
+            log!("Failed to read {}: {}", file.display(), e);
+            Response::new(500)
+        }
+    }
+}
+
+fn not_found_page(file: Option<&Path>) -> Response {
+    match file {
+        Some(file) => file_response(404, file),
+        None => Response::new(404),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{request::Request, static_files::TempDir};
+
+    fn request(method: &str, path: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, path);
+        Request::parse(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(response.body.as_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(
+            r#"
+            [server]
+            listen = "0.0.0.0:8080"
+            metrics = "/metrics"
+
+            [thread_pool]
+            workers = 2
+            scheduling = "shared"
+            max_workers = 8
+
+            [limits]
+            keep_alive_timeout = 2.5
+            max_headers = 50
+            max_body_size = 1024
+
+            [logging]
+            format = "json"
+
+            [[routes]]
+            path = "/health"
+            body = "OK"
+            high_priority = true
+            "#,
+        )
+        .unwrap();
+        assert_eq!("0.0.0.0:8080", config.server.listen);
+        assert!(config.server.compression);
+        assert_eq!(Scheduling::Shared, config.thread_pool.scheduling);
+        assert_eq!(Some(8), config.thread_pool.max_workers);
+        assert_eq!(LogFormat::Json, config.logging.format);
+        assert_eq!("GET", config.routes[0].method);
+
+        let server_config = config.server_config();
+        assert_eq!(
+            Duration::from_millis(2500),
+            server_config.keep_alive_timeout
+        );
+        assert_eq!(50, server_config.max_headers);
+        assert_eq!(1024, server_config.max_body_size);
+        assert_eq!(
+            ServerConfig::default().write_timeout,
+            server_config.write_timeout
+        );
+
+        assert_eq!(Config::default(), Config::parse("").unwrap());
+        // The config shipped with the binary
+        Config::parse(include_str!("../web-server.toml")).unwrap();
+    }
+
+    #[test]
+    fn reject_invalid_config() {
+        for (text, error) in [
+            ("[server]\nport = 80", "unknown field `port`"),
+            (
+                "[thread_pool]\nscheduling = \"fifo\"",
+                "unknown variant `fifo`",
+            ),
+            ("[limits]\nwrite_timeout = \"30s\"", "invalid type"),
+            (
+                "[server]\nlisten = \"localhost\"",
+                "invalid server.listen: localhost: ",
+            ),
+            (
+                "[server]\nhttps_redirect = \"127.0.0.1:80\"",
+                "invalid server.https_redirect: requires the [tls] section",
+            ),
+            (
+                "[thread_pool]\nworkers = 4\nmax_workers = 2",
+                "invalid thread_pool.max_workers: must be at least thread_pool.workers",
+            ),
+            (
+                "[thread_pool]\nscheduling = \"round-robin\"\nmax_workers = 8",
+                "invalid thread_pool.max_workers: requires the \"shared\" scheduling",
+            ),
+            (
+                "[limits]\nheader_read_timeout = 0",
+                "invalid limits.header_read_timeout: must be a positive number of seconds",
+            ),
+            (
+                "[limits]\nmax_body_size = 0",
+                "invalid limits.max_body_size: must be positive",
+            ),
+            (
+                "[[routes]]\npath = \"/*a/b\"\nbody = \"\"",
+                "invalid routes[0].path: can only have a wildcard as the last segment",
+            ),
+            (
+                "[[routes]]\npath = \"/\"\nbody = \"\"\nfile = \"a.html\"",
+                "invalid routes[0]: needs exactly one of file, body and redirect",
+            ),
+            (
+                "[[routes]]\npath = \"/\"\nredirect = \"/a\"\nstatus = 200",
+                "invalid routes[0].status: must be a redirection (3xx) with redirect",
+            ),
+            (
+                "[[routes]]\nmethod = \"get\"\npath = \"/\"\nbody = \"\"",
+                "invalid routes[0].method: must be an upper-case method like GET",
+            ),
+        ] {
+            let e = Config::parse(text).err().unwrap().to_string();
+            assert!(e.contains(error), "{:?} isn't in {:?}", error, e);
+        }
+    }
+
+    #[test]
+    fn build_router() {
+        let dir = TempDir::new("config");
+        let dir = &dir.0;
+        let public = dir.join("public");
+        fs::create_dir_all(&public).unwrap();
+        fs::write(public.join("a.txt"), "a").unwrap();
+        fs::write(dir.join("hello.html"), "<p>hello</p>").unwrap();
+        fs::write(dir.join("404.html"), "<p>missing</p>").unwrap();
+        let text = format!(
+            r#"
+            [server]
+            metrics = "/metrics"
+            not_found = "{dir}/404.html"
+
+            [static_files]
+            root = "{dir}/public"
+            cache_control = ""
+
+            [logging]
+            access_log = false
+
+            [[routes]]
+            path = "/"
+            file = "{dir}/hello.html"
+
+            [[routes]]
+            method = "POST"
+            path = "/echo"
+            body = "created"
+            status = 201
+            headers = {{ "Cache-Control" = "no-store" }}
+
+            [[routes]]
+            path = "/old/*path"
+            redirect = "/new"
+            "#,
+            dir = dir.display()
+        );
+        let config = Config::parse(&text).unwrap();
+        let metrics = Metrics::new();
+        let router = config.router(&metrics).unwrap();
+
+        let response = router.handle(&mut request("GET", "/"));
+        assert_eq!(
+            Some("text/html; charset=utf-8"),
+            response.header("Content-Type")
+        );
+        assert_eq!("<p>hello</p>", body(&response));
+        let response = router.handle(&mut request("POST", "/echo"));
+        assert_eq!(201, response.status);
+        assert_eq!(Some("no-store"), response.header("Cache-Control"));
+        assert_eq!("created", body(&response));
+        let response = router.handle(&mut request("GET", "/old/a"));
+        assert_eq!(308, response.status);
+        assert_eq!(Some("/new"), response.header("Location"));
+
+        let response = router.handle(&mut request("GET", "/a.txt"));
+        assert_eq!(200, response.status);
+        assert_eq!(None, response.header("Cache-Control"));
+        let response = router.handle(&mut request("GET", "/b.txt"));
+        assert_eq!(404, response.status);
+        assert_eq!("<p>missing</p>", body(&response));
+        for method in ["POST", "PUT"] {
+            let response = router.handle(&mut request(method, "/b.txt"));
+            assert_eq!(404, response.status);
+            assert_eq!("<p>missing</p>", body(&response));
+        }
+
+        let response = router.handle(&mut request("GET", "/metrics"));
+        assert!(body(&response).contains("route=\"/echo\",status=\"201\"} 1\n"));
+        assert_eq!(
+            crate::thread_pool::Priority::High,
+            router.priority("/metrics")
+        );
+
+        // Missing files are reported when building
+        let config = Config::parse(&text.replace("hello.html", "missing.html")).unwrap();
+        let e = config.router(&metrics).err().unwrap().to_string();
+        assert!(e.starts_with("invalid routes[0].file: "), "{}", e);
+    }
+
+    #[test]
+    fn restart_required() {
+        let running = Config::default();
+        let mut new = Config::default();
+        new.thread_pool.workers = 8;
+        new.limits.keep_alive_timeout = 1.0;
+        assert!(running.restart_required(&new).is_empty());
+
+        new.server.listen = "127.0.0.1:8080".to_string();
+        new.thread_pool.scheduling = Scheduling::Shared;
+        assert_eq!(
+            vec!["server.listen", "thread_pool.scheduling"],
+            running.restart_required(&new)
+        );
+    }
+}
@@ -0,0 +1,38 @@
+// Copyright 2025 Yunze Xu
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub mod access_log;
+pub mod compression;
+pub mod config;
+pub mod metrics;
+pub mod middleware;
+pub mod request;
+pub mod response;
+pub mod router;
+pub mod server;
+pub mod static_files;
+pub mod thread_pool;
+pub mod tls;
+
+#[doc(hidden)]
+pub use chrono;
+
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        let now = $crate::chrono::Local::now();
+        print!("{} - {} - ", now.format("%Y-%m-%d %H:%M:%S%.3f"), std::thread::current().name().unwrap_or("unknown"));
+        println!($($arg)*);
+    };
+}
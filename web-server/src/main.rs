@@ -12,97 +12,88 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-mod thread_pool;
-use crate::thread_pool::ThreadPool;
-use std::{
-    io::{BufRead, BufReader, Write},
-    net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex, atomic::AtomicBool},
-    thread,
-    time::Duration,
-};
+use std::{fmt, path::PathBuf, process};
+use web_server::{config::Config, log, metrics::Metrics, server::Server};
 
-#[macro_export]
-macro_rules! log {
-    ($($arg:tt)*) => {
-        let now = chrono::Local::now();
-        print!("{} - {} - ", now.format("%Y-%m-%d %H:%M:%S%.3f"), thread::current().name().unwrap_or("unknown"));
-        println!($($arg)*);
-    };
-}
+// The config without a config file
+const DEFAULT_CONFIG: &str = include_str!("../web-server.toml");
 
 fn main() {
-    let args = std::env::args().collect::<Vec<String>>();
-    let url = match args.len() {
-        1 => "127.0.0.1:7878",
-        _ => args[1].as_str(),
-    };
-    log!("Listening on {}", &url);
+    let path = std::env::args().nth(1).map(PathBuf::from);
+    let config = match &path {
+        Some(path) => Config::load(path),
+        None => Config::parse(DEFAULT_CONFIG),
+    }
+    .unwrap_or_else(|e| exit(e));
+    let metrics = Metrics::new();
+    let router = config.router(&metrics).unwrap_or_else(|e| exit(e));
+    let tls = config.tls().unwrap_or_else(|e| exit(e));
 
-    let listener = TcpListener::bind(url).unwrap();
-    listener.set_nonblocking(true).unwrap();
-    let running = Arc::new(AtomicBool::new(true));
+    let listen = &config.server.listen;
+    log!("Listening on {}", listen);
+    let mut server = Server::bind(listen, router, config.thread_pool())
+        .unwrap_or_else(|e| exit(format!("failed to listen on {}: {}", listen, e)))
+        .with_config(config.server_config())
+        .with_metrics(&metrics);
+    if let Some(tls) = &tls {
+        server = server.with_tls(tls);
+    }
+    if let Some(addr) = &config.server.https_redirect {
+        log!("Redirecting to HTTPS from {}", addr);
+        server = server
+            .with_https_redirect(addr)
+            .unwrap_or_else(|e| exit(format!("failed to listen on {}: {}", addr, e)));
+    }
 
-    let running_clone = running.clone();
+    let shutdown = server.shutdown_handle();
     ctrlc::set_handler(move || {
-        running_clone.store(false, std::sync::atomic::Ordering::Release);
-        log!("The server is shutting down and will exit after accepting another connection");
+        shutdown.shutdown();
+        log!("The server is shutting down");
     })
     .unwrap();
+    #[cfg(unix)]
+    if let Some(path) = path {
+        reload_on_sighup(path, config, metrics, server.reload_handle());
+    }
+    server.run();
+}
+
+fn exit(e: impl fmt::Display) -> ! {
+    eprintln!("{}", e);
+    process::exit(1)
+}
+
+/// Reloads the config file on every SIGHUP, a config that fails to load leaves the server as is.
+#[cfg(unix)]
+fn reload_on_sighup(
+    path: PathBuf,
+    mut running: Config,
+    metrics: Metrics,
+    handle: web_server::server::ReloadHandle,
+) {
+    use signal_hook::{consts::SIGHUP, iterator::Signals};
 
-    let pool = ThreadPool::new(4);
-    let pool = Arc::new(Mutex::new(pool));
-    let pool_clone = pool.clone();
-    thread::Builder::new()
-        .name(String::from("acceptor"))
+    let mut signals = Signals::new([SIGHUP]).unwrap();
+    std::thread::Builder::new()
+        .name(String::from("reloader"))
         .spawn(move || {
-            loop {
-                match listener.accept() {
-                    Ok((stream, addr)) => {
-                        log!("Accepted connection from {}", addr);
-                        pool_clone
-                            .lock()
-                            .unwrap()
-                            .execute(move || handle_connection(stream));
-                    }
-                    Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                        if !running.load(std::sync::atomic::Ordering::Acquire) {
-                            log!("Server has been shut down, exiting");
-                            return;
-                        }
-                        // TODO: avoid busy wait
-                        thread::sleep(std::time::Duration::from_millis(100));
+            for _ in signals.forever() {
+                log!("Reloading {}", path.display());
+                let reload = Config::load(&path).and_then(|config| {
+                    let reload = config.reload(&running, &metrics)?;
+                    Ok((config, reload))
+                });
+                match reload {
+                    Ok((config, reload)) => {
+                        handle.reload(reload);
+                        // The other settings are only compared to tell a restart is needed
+                        running.thread_pool.workers = config.thread_pool.workers;
                     }
                     Err(e) => {
-                        log!("Failed to accept connection: {}", e);
-                        return;
+                        log!("Failed to reload {}: {}", path.display(), e);
                     }
                 }
             }
         })
-        .unwrap()
-        .join()
         .unwrap();
-    pool.lock().unwrap().shutdown();
-}
-
-fn handle_connection(mut stream: TcpStream) {
-    let mut buf_reader = BufReader::new(&stream);
-    let mut line = String::new();
-    buf_reader.read_line(&mut line).unwrap();
-
-    let (status_line, filename) = match line.as_str() {
-        "GET / HTTP/1.1" => ("HTTP/1.1 200 OK", "hello.html"),
-        "GET /sleep HTTP/1.1" => {
-            thread::sleep(Duration::from_millis(500));
-            ("HTTP/1.1 200 OK", "hello.html")
-        }
-        _ => ("HTTP/1.1 404 NOT FOUND", "404.html"),
-    };
-    let content = std::fs::read_to_string(filename).unwrap();
-    let response = format!(
-        "{status_line}\r\nContent-Length: {}\r\n\r\n{content}",
-        content.len()
-    );
-    stream.write_all(response.as_bytes()).unwrap();
 }
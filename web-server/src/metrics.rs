@@ -0,0 +1,432 @@
+// Copyright 2025 Yunze Xu
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    middleware::Middleware,
+    request::Request,
+    response::Response,
+    thread_pool::{Monitor, WorkerStats},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+/// The upper bounds of the latency buckets in seconds, the default of the Prometheus clients.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Collects the metrics of a server and exposes them in the Prometheus text format, e.g.
+///
+/// ```
+/// use web_server::{metrics::Metrics, router::Router, server::Server, thread_pool::ThreadPool};
+///
+/// let metrics = Metrics::new();
+/// let mut router = Router::new();
+/// router.wrap(metrics.clone()).get("/metrics", metrics.handler());
+/// let server = Server::bind("127.0.0.1:0", router, ThreadPool::new(4))
+///     .unwrap()
+///     .with_metrics(&metrics);
+/// ```
+///
+/// As middleware, it counts the requests in `http_requests_total` and observes their latency in
+/// the `http_request_duration_seconds` histogram, both labeled by the method and the pattern of
+/// the matched route rather than the path, so that the number of series stays bounded. Requests
+/// that match no route have the `unmatched` route. Add it first to time the other middleware
+/// too, a streamed body isn't included though.
+///
+/// The requests that don't reach the router aren't counted, i.e. the ones that the server answers
+/// itself because they can't be parsed or read in time (e.g. `400`, `408`, `413` and `431`), and
+/// the connections rejected with `503` when the thread pool is full.
+///
+/// [`Server::with_metrics`] adds the `http_connections_active` gauge and the stats of the thread
+/// pool, including whether each worker is busy and how many jobs wait in its queue.
+///
+/// [`Server::with_metrics`]: crate::server::Server::with_metrics
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requests: Mutex<BTreeMap<RequestLabels, Series>>,
+    connections: AtomicUsize,
+    thread_pool: Mutex<Option<Monitor>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct RequestLabels {
+    method: &'static str,
+    route: String,
+}
+
+/// The statuses and latency of the requests with the same labels.
+#[derive(Default)]
+struct Series {
+    statuses: BTreeMap<u16, u64>,
+    // The number of requests in each bucket, not cumulative
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+struct MetricsStart(Instant);
+
+/// Counts an open connection until it's dropped.
+pub(crate) struct ConnectionGuard(Arc<Inner>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a handler that responds with the metrics, usually routed as `GET /metrics`.
+    pub fn handler(&self) -> impl Fn(&Request) -> Response + Send + Sync + 'static {
+        let metrics = self.clone();
+        move |_| {
+            Response::new(200)
+                .with_header("Content-Type", "text/plain; version=0.0.4; charset=utf-8")
+                .with_header("Cache-Control", "no-store")
+                .with_body(metrics.render())
+        }
+    }
+
+    /// Records a request answered with `status` after `duration`.
+    pub fn observe(&self, request: &Request, status: u16, duration: Duration) {
+        let labels = RequestLabels {
+            method: method_label(&request.method),
+            route: request.route.as_deref().unwrap_or("unmatched").to_string(),
+        };
+        let seconds = duration.as_secs_f64();
+        let mut requests = self.inner.requests.lock().unwrap();
+        let series = requests.entry(labels).or_default();
+        *series.statuses.entry(status).or_default() += 1;
+        if let Some(i) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            series.buckets[i] += 1;
+        }
+        series.count += 1;
+        series.sum += seconds;
+    }
+
+    pub(crate) fn set_thread_pool(&self, monitor: Monitor) {
+        *self.inner.thread_pool.lock().unwrap() = Some(monitor);
+    }
+
+    pub(crate) fn open_connection(&self) -> ConnectionGuard {
+        self.inner.connections.fetch_add(1, Ordering::AcqRel);
+        ConnectionGuard(self.inner.clone())
+    }
+
+    /// Formats the metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        let requests = self.inner.requests.lock().unwrap();
+        header(
+            &mut text,
+            "http_requests_total",
+            "counter",
+            "The number of requests answered.",
+        );
+        for (labels, series) in requests.iter() {
+            for (status, count) in &series.statuses {
+                let _ = writeln!(
+                    text,
+                    "http_requests_total{{method=\"{}\",route={},status=\"{}\"}} {}",
+                    labels.method,
+                    label_value(&labels.route),
+                    status,
+                    count
+                );
+            }
+        }
+        header(
+            &mut text,
+            "http_request_duration_seconds",
+            "histogram",
+            "The time to produce the responses in seconds.",
+        );
+        for (labels, series) in requests.iter() {
+            let labels = format!(
+                "method=\"{}\",route={}",
+                labels.method,
+                label_value(&labels.route)
+            );
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(series.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    text,
+                    "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, cumulative
+                );
+            }
+            let _ = writeln!(
+                text,
+                "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, series.count
+            );
+            let _ = writeln!(
+                text,
+                "http_request_duration_seconds_sum{{{}}} {}",
+                labels, series.sum
+            );
+            let _ = writeln!(
+                text,
+                "http_request_duration_seconds_count{{{}}} {}",
+                labels, series.count
+            );
+        }
+        drop(requests);
+
+        header(
+            &mut text,
+            "http_connections_active",
+            "gauge",
+            "The number of open connections.",
+        );
+        let connections = self.inner.connections.load(Ordering::Acquire);
+        let _ = writeln!(text, "http_connections_active {}", connections);
+
+        let Some(stats) = self
+            .inner
+            .thread_pool
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(Monitor::stats)
+        else {
+            return text;
+        };
+        let busy = stats.per_worker.iter().filter(|worker| worker.busy).count();
+        for (name, kind, help, value) in [
+            (
+                "thread_pool_workers",
+                "gauge",
+                "The number of workers, except the reserved ones.",
+                stats.workers as u64,
+            ),
+            (
+                "thread_pool_busy_workers",
+                "gauge",
+                "The number of workers running a job, including the reserved ones.",
+                busy as u64,
+            ),
+            (
+                "thread_pool_queued_jobs",
+                "gauge",
+                "The number of jobs waiting in the queues.",
+                stats.queued_jobs as u64,
+            ),
+            (
+                "thread_pool_completed_jobs_total",
+                "counter",
+                "The number of jobs finished.",
+                stats.completed_jobs,
+            ),
+        ] {
+            header(&mut text, name, kind, help);
+            let _ = writeln!(text, "{} {}", name, value);
+        }
+        let workers = &stats.per_worker;
+        worker_metric(
+            &mut text,
+            ("thread_pool_worker_busy", "gauge"),
+            "Whether the worker is running a job.",
+            workers,
+            |worker| u64::from(worker.busy),
+        );
+        worker_metric(
+            &mut text,
+            ("thread_pool_worker_queued_jobs", "gauge"),
+            "The number of jobs waiting in the queue of the worker, which all workers share with \
+             the shared scheduling.",
+            workers,
+            |worker| worker.queued_jobs as u64,
+        );
+        worker_metric(
+            &mut text,
+            ("thread_pool_worker_completed_jobs_total", "counter"),
+            "The number of jobs finished by the worker.",
+            workers,
+            |worker| worker.completed_jobs,
+        );
+        text
+    }
+}
+
+impl Middleware for Metrics {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        request.extensions.insert(MetricsStart(Instant::now()));
+        None
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        if let Some(start) = request.extensions.get::<MetricsStart>() {
+            self.observe(request, response.status, start.0.elapsed());
+        }
+    }
+}
+
+fn header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+}
+
+fn worker_metric<F: Fn(&WorkerStats) -> u64>(
+    text: &mut String,
+    (name, kind): (&str, &str),
+    help: &str,
+    workers: &[WorkerStats],
+    value: F,
+) {
+    header(text, name, kind, help);
+    for worker in workers {
+        let _ = writeln!(
+            text,
+            "{}{{worker=\"{}\",reserved=\"{}\"}} {}",
+            name,
+            worker.id,
+            worker.reserved,
+            value(worker)
+        );
+    }
+}
+
+/// Maps the method to a label, so that clients can't add series by sending made-up methods.
+fn method_label(method: &str) -> &'static str {
+    const METHODS: [&str; 9] = [
+        "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+    ];
+    METHODS
+        .into_iter()
+        .find(|known| *known == method)
+        .unwrap_or("OTHER")
+}
+
+/// Quotes a label value, escaping `\`, `"` and line feeds.
+fn label_value(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{router::Router, thread_pool::ThreadPool};
+
+    fn request(method: &str, path: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, path);
+        Request::parse(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::new();
+        let mut router = Router::new();
+        router
+            .wrap(metrics.clone())
+            .get("/users/:id", |_| Response::new(200))
+            .get("/metrics", metrics.handler());
+        for path in ["/users/1", "/users/2", "/missing"] {
+            router.handle(&mut request("GET", path));
+        }
+        router.handle(&mut request("BREW", "/users/1"));
+        let mut slow = request("GET", "/slow");
+        slow.route = Some("/slow".to_string());
+        metrics.observe(&slow, 200, Duration::from_millis(30));
+
+        let response = router.handle(&mut request("GET", "/metrics"));
+        assert_eq!(
+            Some("text/plain; version=0.0.4; charset=utf-8"),
+            response.header("Content-Type")
+        );
+        let text = std::str::from_utf8(response.body.as_bytes().unwrap()).unwrap();
+        for line in [
+            "# TYPE http_requests_total counter",
+            "http_requests_total{method=\"GET\",route=\"/users/:id\",status=\"200\"} 2",
+            "http_requests_total{method=\"GET\",route=\"unmatched\",status=\"404\"} 1",
+            "http_requests_total{method=\"OTHER\",route=\"unmatched\",status=\"405\"} 1",
+            "# TYPE http_request_duration_seconds histogram",
+            "http_request_duration_seconds_bucket{method=\"GET\",route=\"/slow\",le=\"0.025\"} 0",
+            "http_request_duration_seconds_bucket{method=\"GET\",route=\"/slow\",le=\"0.05\"} 1",
+            "http_request_duration_seconds_bucket{method=\"GET\",route=\"/slow\",le=\"+Inf\"} 1",
+            "http_request_duration_seconds_sum{method=\"GET\",route=\"/slow\"} 0.03",
+            "http_request_duration_seconds_count{method=\"GET\",route=\"/users/:id\"} 2",
+            "http_connections_active 0",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} is missing in\n{}",
+                line,
+                text
+            );
+        }
+        // The request to /metrics is observed after rendering
+        assert!(!text.contains("route=\"/metrics\""));
+        assert!(!text.contains("thread_pool_"));
+
+        let _connection = metrics.open_connection();
+        let mut pool = ThreadPool::new(2);
+        metrics.set_thread_pool(pool.monitor());
+        let text = metrics.render();
+        for line in [
+            "http_connections_active 1",
+            "thread_pool_workers 2",
+            "thread_pool_busy_workers 0",
+            "thread_pool_queued_jobs 0",
+            "# TYPE thread_pool_worker_busy gauge",
+            "thread_pool_worker_busy{worker=\"1\",reserved=\"false\"} 0",
+            "thread_pool_worker_queued_jobs{worker=\"0\",reserved=\"false\"} 0",
+            "thread_pool_worker_completed_jobs_total{worker=\"1\",reserved=\"false\"} 0",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} is missing in\n{}",
+                line,
+                text
+            );
+        }
+        pool.shutdown();
+    }
+
+    #[test]
+    fn escape_label_values() {
+        assert_eq!(r#""/a\"b\\c\n""#, label_value("/a\"b\\c\n"));
+        assert_eq!("GET", method_label("GET"));
+        assert_eq!("OTHER", method_label("get"));
+    }
+}
@@ -0,0 +1,553 @@
+// Copyright 2025 Yunze Xu
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+pub use crate::access_log::AccessLog;
+use crate::{compression::add_vary, request::Request, response::Response};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Runs around the handlers of a [`Router`](crate::router::Router), either for all requests
+/// ([`Router::wrap`](crate::router::Router::wrap)) or for a single route
+/// ([`Router::wrap_route`](crate::router::Router::wrap_route)).
+///
+/// The `before` hooks run in the order the middleware was added, and the first one that returns
+/// a response short-circuits: the remaining `before` hooks and the handler are skipped. Then the
+/// `after` hooks of the middleware whose `before` ran are called in reverse order, so the first
+/// middleware sees the final response. State can be passed from `before` to `after` through
+/// [`Request::extensions`].
+pub trait Middleware: Send + Sync + 'static {
+    /// Inspects or modifies the request before the handler, returns a response to answer the
+    /// request without calling the handler.
+    fn before(&self, _request: &mut Request) -> Option<Response> {
+        None
+    }
+
+    /// Inspects or modifies the response after the handler.
+    fn after(&self, _request: &Request, _response: &mut Response) {}
+}
+
+/// Runs `handler` wrapped by `middleware`, see [`Middleware`].
+pub(crate) fn run<F>(
+    middleware: &[Box<dyn Middleware>],
+    request: &mut Request,
+    handler: F,
+) -> Response
+where
+    F: FnOnce(&mut Request) -> Response,
+{
+    let mut ran = 0;
+    let mut response = None;
+    for m in middleware {
+        ran += 1;
+        if let Some(short_circuit) = m.before(request) {
+            response = Some(short_circuit);
+            break;
+        }
+    }
+    let mut response = response.unwrap_or_else(|| handler(request));
+    for m in middleware[..ran].iter().rev() {
+        m.after(request, &mut response);
+    }
+    response
+}
+
+/// Tags every request with an ID, echoed in the `X-Request-Id` response header so that a client
+/// can refer to a request in a bug report, and available to handlers by [`RequestId::of`].
+///
+/// A valid ID sent by the client (e.g. set by a proxy in front of the server) is kept, otherwise
+/// a new one is generated.
+pub struct RequestId {
+    header: String,
+    prefix: u32,
+    next: AtomicU64,
+}
+
+struct Id(String);
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RequestId {
+    pub fn new() -> Self {
+        // Distinguishes the IDs of different runs of the server
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        RequestId {
+            header: "X-Request-Id".to_string(),
+            prefix: nanos ^ std::process::id().rotate_left(16),
+            next: AtomicU64::new(1),
+        }
+    }
+
+    /// Sets the header that carries the ID, `X-Request-Id` by default.
+    pub fn with_header(mut self, header: &str) -> Self {
+        self.header = header.to_string();
+        self
+    }
+
+    /// Returns the ID of a request that went through this middleware.
+    pub fn of(request: &Request) -> Option<&str> {
+        request.extensions.get::<Id>().map(|id| id.0.as_str())
+    }
+}
+
+impl Middleware for RequestId {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        let id = match request.header(&self.header) {
+            Some(id)
+                if !id.is_empty()
+                    && id.len() <= 128
+                    && id.bytes().all(|b| b.is_ascii_graphic()) =>
+            {
+                id.to_string()
+            }
+            _ => format!(
+                "{:08x}-{:08x}",
+                self.prefix,
+                self.next.fetch_add(1, Ordering::Relaxed)
+            ),
+        };
+        request.extensions.insert(Id(id));
+        None
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        if let Some(id) = RequestId::of(request) {
+            response.set_header(&self.header, id);
+        }
+    }
+}
+
+/// Cross-Origin Resource Sharing, which allows scripts of other origins to call the server, see
+/// <https://fetch.spec.whatwg.org/#http-cors-protocol>.
+///
+/// Preflight requests (`OPTIONS` with `Access-Control-Request-Method`) are answered with
+/// `204 No Content` without reaching the router, so add it by [`Router::wrap`] rather than to a
+/// single route.
+///
+/// [`Router::wrap`]: crate::router::Router::wrap
+pub struct Cors {
+    // `None` allows any origin
+    origins: Option<Vec<String>>,
+    methods: Vec<String>,
+    // Empty allows the headers the preflight asks for
+    headers: Vec<String>,
+    expose_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cors {
+    /// Allows any origin to send `GET`, `HEAD` and `POST` requests with any headers.
+    pub fn new() -> Self {
+        Cors {
+            origins: None,
+            methods: vec!["GET".to_string(), "HEAD".to_string(), "POST".to_string()],
+            headers: vec![],
+            expose_headers: vec![],
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Only allows these origins, e.g. `https://example.com`.
+    pub fn with_origins(mut self, origins: &[&str]) -> Self {
+        self.origins = Some(origins.iter().map(|origin| origin.to_string()).collect());
+        self
+    }
+
+    pub fn with_methods(mut self, methods: &[&str]) -> Self {
+        self.methods = methods
+            .iter()
+            .map(|method| method.to_ascii_uppercase())
+            .collect();
+        self
+    }
+
+    /// Only allows these request headers instead of any.
+    pub fn with_headers(mut self, headers: &[&str]) -> Self {
+        self.headers = headers.iter().map(|header| header.to_string()).collect();
+        self
+    }
+
+    /// Lets scripts read these response headers besides the safelisted ones.
+    pub fn with_expose_headers(mut self, headers: &[&str]) -> Self {
+        self.expose_headers = headers.iter().map(|header| header.to_string()).collect();
+        self
+    }
+
+    /// Allows requests with cookies or HTTP authentication.
+    pub fn with_credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Sets how long browsers may cache the result of a preflight request.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins
+            .as_ref()
+            .is_none_or(|origins| origins.iter().any(|allowed| allowed == origin))
+    }
+}
+
+impl Middleware for Cors {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        if request.method != "OPTIONS" || request.header("Origin").is_none() {
+            return None;
+        }
+        let method = request.header("Access-Control-Request-Method")?;
+        let mut response = Response::new(204);
+        if !request
+            .header("Origin")
+            .is_some_and(|origin| self.allows(origin))
+            || !self.methods.iter().any(|allowed| allowed == method)
+        {
+            // Without the `Access-Control-Allow-*` headers the browser fails the request
+            return Some(response);
+        }
+        response.set_header("Access-Control-Allow-Methods", self.methods.join(", "));
+        let headers = match (
+            self.headers.is_empty(),
+            request.header("Access-Control-Request-Headers"),
+        ) {
+            (false, _) => Some(self.headers.join(", ")),
+            (true, requested) => requested.map(str::to_string),
+        };
+        if let Some(headers) = headers {
+            response.set_header("Access-Control-Allow-Headers", headers);
+        }
+        add_vary(&mut response, "Access-Control-Request-Headers");
+        if let Some(max_age) = self.max_age {
+            response.set_header("Access-Control-Max-Age", max_age.as_secs().to_string());
+        }
+        Some(response)
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        if self.origins.is_some() || self.credentials {
+            // The allowed origin depends on the request
+            add_vary(response, "Origin");
+        }
+        let Some(origin) = request
+            .header("Origin")
+            .filter(|origin| self.allows(origin))
+        else {
+            return;
+        };
+        // `*` can't be used with credentials, see the fetch standard 3.3.5
+        if self.origins.is_none() && !self.credentials {
+            response.set_header("Access-Control-Allow-Origin", "*");
+        } else {
+            response.set_header("Access-Control-Allow-Origin", origin);
+        }
+        if self.credentials {
+            response.set_header("Access-Control-Allow-Credentials", "true");
+        }
+        if !self.expose_headers.is_empty() && request.method != "OPTIONS" {
+            response.set_header(
+                "Access-Control-Expose-Headers",
+                self.expose_headers.join(", "),
+            );
+        }
+    }
+}
+
+/// Adds headers that harden browsers against content sniffing, clickjacking and referrer leaks,
+/// unless the handler has set them.
+///
+/// `Content-Security-Policy` and `Strict-Transport-Security` depend on the site, so they're
+/// only sent if added by [`SecurityHeaders::with_header`].
+pub struct SecurityHeaders {
+    headers: Vec<(String, String)>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SecurityHeaders {
+    pub fn new() -> Self {
+        SecurityHeaders {
+            headers: [
+                ("X-Content-Type-Options", "nosniff"),
+                ("X-Frame-Options", "DENY"),
+                ("Referrer-Policy", "strict-origin-when-cross-origin"),
+            ]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        }
+    }
+
+    /// Adds a header or replaces the default value of it.
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self = self.without(name);
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Stops sending a header.
+    pub fn without(mut self, name: &str) -> Self {
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn after(&self, _request: &Request, response: &mut Response) {
+        for (name, value) in &self.headers {
+            if response.header(name).is_none() {
+                response.set_header(name, value.as_str());
+            }
+        }
+    }
+}
+
+/// Reports how long the request took in a `Server-Timing` header, which browsers show in their
+/// developer tools.
+///
+/// The duration only covers what runs inside this middleware, so add it first to time the other
+/// middleware too. A streamed body is written after the header is sent, so it isn't included.
+pub struct Timing {
+    name: String,
+}
+
+struct TimingStart(Instant);
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timing {
+    pub fn new() -> Self {
+        Timing {
+            name: "app".to_string(),
+        }
+    }
+
+    /// Sets the metric name, `app` by default.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = name.to_string();
+        self
+    }
+}
+
+impl Middleware for Timing {
+    fn before(&self, request: &mut Request) -> Option<Response> {
+        request.extensions.insert(TimingStart(Instant::now()));
+        None
+    }
+
+    fn after(&self, request: &Request, response: &mut Response) {
+        let Some(start) = request.extensions.get::<TimingStart>() else {
+            return;
+        };
+        let metric = format!(
+            "{};dur={:.3}",
+            self.name,
+            start.0.elapsed().as_secs_f64() * 1000.0
+        );
+        let value = match response.header("Server-Timing") {
+            Some(existing) => format!("{}, {}", existing, metric),
+            None => metric,
+        };
+        response.set_header("Server-Timing", value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn request(raw: &str) -> Request {
+        Request::parse(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    /// Records the hooks it runs, and short-circuits with its status if any.
+    struct Record {
+        name: &'static str,
+        calls: Arc<Mutex<Vec<String>>>,
+        short_circuit: Option<u16>,
+    }
+
+    impl Middleware for Record {
+        fn before(&self, _request: &mut Request) -> Option<Response> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("before {}", self.name));
+            self.short_circuit.map(Response::new)
+        }
+
+        fn after(&self, _request: &Request, response: &mut Response) {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("after {} {}", self.name, response.status));
+        }
+    }
+
+    #[test]
+    fn run_in_order() {
+        let calls = Arc::new(Mutex::new(vec![]));
+        let record = |name, short_circuit| -> Box<dyn Middleware> {
+            Box::new(Record {
+                name,
+                calls: calls.clone(),
+                short_circuit,
+            })
+        };
+        let middleware = vec![record("a", None), record("b", None)];
+        let response = run(&middleware, &mut request("GET / HTTP/1.1\r\n\r\n"), |_| {
+            Response::new(200)
+        });
+        assert_eq!(200, response.status);
+        assert_eq!(
+            vec!["before a", "before b", "after b 200", "after a 200"],
+            *calls.lock().unwrap()
+        );
+
+        calls.lock().unwrap().clear();
+        let middleware = vec![record("a", None), record("b", Some(401)), record("c", None)];
+        let response = run(
+            &middleware,
+            &mut request("GET / HTTP/1.1\r\n\r\n"),
+            |_| unreachable!(),
+        );
+        assert_eq!(401, response.status);
+        assert_eq!(
+            vec!["before a", "before b", "after b 401", "after a 401"],
+            *calls.lock().unwrap()
+        );
+    }
+
+    #[test]
+    fn request_id() {
+        let middleware: Vec<Box<dyn Middleware>> = vec![Box::new(RequestId::new())];
+        let handle = |raw: &str| {
+            run(&middleware, &mut request(raw), |request| {
+                Response::new(200).with_body(RequestId::of(request).unwrap().to_string())
+            })
+        };
+
+        let first = handle("GET / HTTP/1.1\r\n\r\n");
+        let second = handle("GET / HTTP/1.1\r\n\r\n");
+        let id = first.header("X-Request-Id").unwrap();
+        assert_eq!(Some(id.as_bytes()), first.body.as_bytes());
+        assert_ne!(Some(id), second.header("X-Request-Id"));
+
+        let response = handle("GET / HTTP/1.1\r\nX-Request-Id: abc-123\r\n\r\n");
+        assert_eq!(Some("abc-123"), response.header("X-Request-Id"));
+        let response = handle("GET / HTTP/1.1\r\nX-Request-Id: a b\r\n\r\n");
+        assert_ne!(Some("a b"), response.header("X-Request-Id"));
+    }
+
+    #[test]
+    fn cors() {
+        let handle = |cors: Cors, raw: &str| {
+            let middleware: Vec<Box<dyn Middleware>> = vec![Box::new(cors)];
+            run(&middleware, &mut request(raw), |_| Response::new(200))
+        };
+
+        let response = handle(
+            Cors::new(),
+            "GET / HTTP/1.1\r\nOrigin: https://a.com\r\n\r\n",
+        );
+        assert_eq!(Some("*"), response.header("Access-Control-Allow-Origin"));
+        assert_eq!(None, response.header("Vary"));
+
+        let cors = || {
+            Cors::new()
+                .with_origins(&["https://a.com"])
+                .with_methods(&["GET", "PUT"])
+                .with_credentials(true)
+                .with_max_age(Duration::from_secs(600))
+        };
+        let preflight = "OPTIONS /users HTTP/1.1\r\nOrigin: https://a.com\r\n\
+                         Access-Control-Request-Method: PUT\r\n\
+                         Access-Control-Request-Headers: content-type\r\n\r\n";
+        let response = handle(cors(), preflight);
+        assert_eq!(204, response.status);
+        assert_eq!(
+            Some("https://a.com"),
+            response.header("Access-Control-Allow-Origin")
+        );
+        assert_eq!(
+            Some("GET, PUT"),
+            response.header("Access-Control-Allow-Methods")
+        );
+        assert_eq!(
+            Some("content-type"),
+            response.header("Access-Control-Allow-Headers")
+        );
+        assert_eq!(Some("600"), response.header("Access-Control-Max-Age"));
+        assert_eq!(
+            Some("true"),
+            response.header("Access-Control-Allow-Credentials")
+        );
+
+        let response = handle(cors(), &preflight.replace("a.com", "b.com"));
+        assert_eq!(204, response.status);
+        assert_eq!(None, response.header("Access-Control-Allow-Origin"));
+        assert_eq!(None, response.header("Access-Control-Allow-Methods"));
+
+        let response = handle(cors(), "GET / HTTP/1.1\r\nOrigin: https://b.com\r\n\r\n");
+        assert_eq!(None, response.header("Access-Control-Allow-Origin"));
+        assert_eq!(Some("Origin"), response.header("Vary"));
+    }
+
+    #[test]
+    fn response_headers() {
+        let middleware: Vec<Box<dyn Middleware>> = vec![
+            Box::new(Timing::new()),
+            Box::new(SecurityHeaders::new().with_header("X-Frame-Options", "SAMEORIGIN")),
+        ];
+        let response = run(&middleware, &mut request("GET / HTTP/1.1\r\n\r\n"), |_| {
+            Response::new(200).with_header("Referrer-Policy", "no-referrer")
+        });
+        assert_eq!(Some("nosniff"), response.header("X-Content-Type-Options"));
+        assert_eq!(Some("SAMEORIGIN"), response.header("X-Frame-Options"));
+        assert_eq!(Some("no-referrer"), response.header("Referrer-Policy"));
+        assert!(
+            response
+                .header("Server-Timing")
+                .unwrap()
+                .starts_with("app;dur=")
+        );
+    }
+}
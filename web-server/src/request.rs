@@ -0,0 +1,603 @@
+// Copyright 2025 Yunze Xu
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    fmt,
+    io::{self, BufRead, Read},
+    net::SocketAddr,
+};
+
+/// An HTTP/1.x request read from a connection.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// The path of the request target, without the query string.
+    pub path: String,
+    /// The raw query string after `?`, if any.
+    pub query: Option<String>,
+    pub version: String,
+    /// The headers in the order they were received, with names as sent by the client.
+    pub headers: Vec<(String, String)>,
+    /// The body, decoded if it was sent with `Transfer-Encoding: chunked`.
+    pub body: Vec<u8>,
+    /// The path parameters captured by the matched route, see [`crate::router::Router`].
+    pub params: Vec<(String, String)>,
+    /// The pattern of the matched route, e.g. `/users/:id`, set by the router.
+    pub route: Option<String>,
+    /// The address of the client, set by the server.
+    pub remote_addr: Option<SocketAddr>,
+    /// Values attached by middleware, see [`crate::middleware::Middleware`].
+    pub extensions: Extensions,
+}
+
+/// Values of arbitrary types attached to a [`Request`], at most one per type.
+#[derive(Default)]
+pub struct Extensions {
+    values: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    /// Attaches `value`, returning the previous value of the same type.
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.values
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok().map(|previous| *previous))
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        let value = self.values.remove(&TypeId::of::<T>())?;
+        value.downcast().ok().map(|value| *value)
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions")
+            .field("len", &self.values.len())
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Io(io::Error),
+    /// The connection was closed in the middle of a request.
+    UnexpectedEof,
+    BadRequestLine(String),
+    BadHeader(String),
+    BadContentLength(String),
+    BadChunk(String),
+    UnsupportedVersion(String),
+    UnsupportedTransferEncoding(String),
+    /// The request line is longer than the limit on the head.
+    UriTooLong,
+    /// The head is larger than the limit, or has too many header fields.
+    HeadersTooLarge,
+    /// The body is larger than the limit.
+    PayloadTooLarge,
+}
+
+/// The default limit on the size of the head of a request, including the request line.
+pub const MAX_HEADER_SIZE: usize = 16 * 1024;
+/// The default limit on the number of header fields of a request.
+pub const MAX_HEADERS: usize = 100;
+/// The default limit on the size of the body of a request.
+pub const MAX_BODY_SIZE: usize = 8 * 1024 * 1024;
+
+impl ParseError {
+    /// The status code to respond with.
+    pub fn status(&self) -> u16 {
+        match self {
+            ParseError::Io(e) if is_timeout(e) => 408,
+            ParseError::UriTooLong => 414,
+            ParseError::HeadersTooLarge => 431,
+            ParseError::PayloadTooLarge => 413,
+            ParseError::UnsupportedVersion(_) => 505,
+            ParseError::UnsupportedTransferEncoding(_) => 501,
+            _ => 400,
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Io(e) => write!(f, "I/O error: {}", e),
+            ParseError::UnexpectedEof => write!(f, "connection closed in the middle of a request"),
+            ParseError::BadRequestLine(line) => write!(f, "malformed request line: {:?}", line),
+            ParseError::BadHeader(line) => write!(f, "malformed header: {:?}", line),
+            ParseError::BadContentLength(value) => write!(f, "invalid Content-Length: {:?}", value),
+            ParseError::BadChunk(line) => write!(f, "malformed chunk: {:?}", line),
+            ParseError::UnsupportedVersion(version) => {
+                write!(f, "unsupported HTTP version: {:?}", version)
+            }
+            ParseError::UnsupportedTransferEncoding(value) => {
+                write!(f, "unsupported Transfer-Encoding: {:?}", value)
+            }
+            ParseError::UriTooLong => write!(f, "request line too long"),
+            ParseError::HeadersTooLarge => write!(f, "header fields too large"),
+            ParseError::PayloadTooLarge => write!(f, "body too large"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Whether a read failed because of a read timeout of the socket.
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+impl From<io::Error> for ParseError {
+    fn from(e: io::Error) -> Self {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            ParseError::UnexpectedEof
+        } else {
+            ParseError::Io(e)
+        }
+    }
+}
+
+impl Request {
+    /// Reads the next request from `reader` with the default limits on the head and the body.
+    ///
+    /// Returns `Ok(None)` if the connection was closed before any byte of a request arrived.
+    pub fn parse<R: BufRead>(reader: &mut R) -> Result<Option<Request>, ParseError> {
+        let Some(mut request) = Request::parse_head(reader, MAX_HEADER_SIZE, MAX_HEADERS)? else {
+            return Ok(None);
+        };
+        request.read_body(reader, MAX_HEADER_SIZE, MAX_BODY_SIZE)?;
+        Ok(Some(request))
+    }
+
+    /// Reads the request line and the header fields, which take at most `max_size` bytes and
+    /// `max_headers` fields, so that a client can't make the server buffer an endless head.
+    pub fn parse_head<R: BufRead>(
+        reader: &mut R,
+        max_size: usize,
+        max_headers: usize,
+    ) -> Result<Option<Request>, ParseError> {
+        let mut remaining = max_size;
+        let line = match read_line(reader, &mut remaining) {
+            Ok(Some(line)) => line,
+            Ok(None) => return Ok(None),
+            Err(ParseError::HeadersTooLarge) => return Err(ParseError::UriTooLong),
+            Err(e) => return Err(e),
+        };
+        let mut parts = line.split(' ');
+        let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(method), Some(target), Some(version)) if parts.next().is_none() => {
+                (method, target, version)
+            }
+            _ => return Err(ParseError::BadRequestLine(line)),
+        };
+        if method.is_empty() || !method.bytes().all(is_token) || !target.starts_with('/') {
+            return Err(ParseError::BadRequestLine(line));
+        }
+        if !version.starts_with("HTTP/") {
+            return Err(ParseError::BadRequestLine(line));
+        }
+        if version != "HTTP/1.1" && version != "HTTP/1.0" {
+            return Err(ParseError::UnsupportedVersion(version.to_string()));
+        }
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path.to_string(), Some(query.to_string())),
+            None => (target.to_string(), None),
+        };
+        let mut request = Request {
+            method: method.to_string(),
+            path,
+            query,
+            version: version.to_string(),
+            headers: vec![],
+            body: vec![],
+            params: vec![],
+            route: None,
+            remote_addr: None,
+            extensions: Extensions::default(),
+        };
+
+        loop {
+            let line = read_line(reader, &mut remaining)?.ok_or(ParseError::UnexpectedEof)?;
+            if line.is_empty() {
+                break;
+            }
+            if request.headers.len() == max_headers {
+                return Err(ParseError::HeadersTooLarge);
+            }
+            let (name, value) = match line.split_once(':') {
+                Some((name, value)) if !name.is_empty() && name.bytes().all(is_token) => {
+                    (name, value.trim_matches([' ', '\t']))
+                }
+                _ => return Err(ParseError::BadHeader(line)),
+            };
+            request.headers.push((name.to_string(), value.to_string()));
+        }
+        Ok(Some(request))
+    }
+
+    /// Reads the body framed by `Content-Length` or `Transfer-Encoding: chunked`, after
+    /// [`Request::parse_head`]. A body of more than `max_size` bytes is rejected before it's
+    /// buffered, and each chunk-size line and the trailer may take up to `max_line_size` bytes.
+    pub fn read_body<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+        max_line_size: usize,
+        max_size: usize,
+    ) -> Result<(), ParseError> {
+        let request = self;
+        let codings = request
+            .headers_all("Transfer-Encoding")
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|coding| !coding.is_empty())
+            .collect::<Vec<_>>();
+        if !codings.is_empty() {
+            if codings.len() > 1 || !codings[0].eq_ignore_ascii_case("chunked") {
+                return Err(ParseError::UnsupportedTransferEncoding(codings.join(", ")));
+            }
+            // A request with both could be read differently by a proxy, see RFC 9112 6.1
+            if let Some(value) = request.header("Content-Length") {
+                return Err(ParseError::BadContentLength(value.to_string()));
+            }
+            request.body = read_chunked(reader, max_line_size, max_size)?;
+            return Ok(());
+        }
+
+        let mut content_length = None;
+        for value in request.headers_all("Content-Length") {
+            // Only digits, while `parse` would also take a sign, see RFC 9110 8.6
+            if !value.bytes().all(|c| c.is_ascii_digit()) {
+                return Err(ParseError::BadContentLength(value.to_string()));
+            }
+            let length = value
+                .parse::<usize>()
+                .map_err(|_| ParseError::BadContentLength(value.to_string()))?;
+            if content_length.is_some_and(|previous| previous != length) {
+                return Err(ParseError::BadContentLength(value.to_string()));
+            }
+            content_length = Some(length);
+        }
+        if let Some(length) = content_length {
+            if length > max_size {
+                return Err(ParseError::PayloadTooLarge);
+            }
+            // The buffer grows with the bytes that actually arrive
+            if reader
+                .by_ref()
+                .take(length as u64)
+                .read_to_end(&mut request.body)?
+                != length
+            {
+                return Err(ParseError::UnexpectedEof);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the value of the first header named `name`, ignoring ASCII case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Whether the client wants the connection to stay open after the response, which is the
+    /// default since HTTP/1.1.
+    pub fn keep_alive(&self) -> bool {
+        let mut keep_alive = self.version != "HTTP/1.0";
+        for value in self.headers_all("Connection") {
+            for option in value.split(',').map(str::trim) {
+                if option.eq_ignore_ascii_case("close") {
+                    return false;
+                }
+                if option.eq_ignore_ascii_case("keep-alive") {
+                    keep_alive = true;
+                }
+            }
+        }
+        keep_alive
+    }
+
+    /// Returns the value of the path parameter `name` captured by the router.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the values of all headers named `name`, ignoring ASCII case.
+    pub fn headers_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.headers
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Reads a line terminated by `\r\n` (or a bare `\n`) without the terminator, taking at most
+/// `remaining` bytes from it.
+fn read_line<R: BufRead>(
+    reader: &mut R,
+    remaining: &mut usize,
+) -> Result<Option<String>, ParseError> {
+    let mut line = Vec::new();
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            if line.is_empty() {
+                return Ok(None);
+            }
+            return Err(ParseError::UnexpectedEof);
+        }
+        let (consumed, done) = match buf.iter().position(|&c| c == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (buf.len(), false),
+        };
+        if consumed > *remaining {
+            return Err(ParseError::HeadersTooLarge);
+        }
+        *remaining -= consumed;
+        line.extend_from_slice(&buf[..consumed]);
+        reader.consume(consumed);
+        if done {
+            break;
+        }
+    }
+    line.pop();
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|e| ParseError::BadHeader(String::from_utf8_lossy(e.as_bytes()).into_owned()))
+}
+
+/// Decodes a body sent with `Transfer-Encoding: chunked` of at most `max_size` bytes in total,
+/// discarding the trailer fields.
+fn read_chunked<R: BufRead>(
+    reader: &mut R,
+    max_line_size: usize,
+    max_size: usize,
+) -> Result<Vec<u8>, ParseError> {
+    let mut body = vec![];
+    loop {
+        // Each line around a chunk is bounded on its own, so the number of chunks is only
+        // limited by `max_size`
+        let mut remaining = max_line_size;
+        let line = read_line(reader, &mut remaining)?.ok_or(ParseError::UnexpectedEof)?;
+        // Chunk extensions after `;` are ignored
+        let size = line.split(';').next().unwrap_or_default().trim();
+        if size.is_empty() || !size.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ParseError::BadChunk(line));
+        }
+        let Ok(size) = u64::from_str_radix(size, 16) else {
+            return Err(ParseError::BadChunk(line));
+        };
+        if size == 0 {
+            break;
+        }
+        if size > (max_size - body.len()) as u64 {
+            return Err(ParseError::PayloadTooLarge);
+        }
+        if reader.by_ref().take(size).read_to_end(&mut body)? as u64 != size {
+            return Err(ParseError::UnexpectedEof);
+        }
+        let mut remaining = max_line_size;
+        match read_line(reader, &mut remaining)? {
+            Some(line) if line.is_empty() => {}
+            Some(line) => return Err(ParseError::BadChunk(line)),
+            None => return Err(ParseError::UnexpectedEof),
+        }
+    }
+    // The trailer fields are bounded together like the head
+    let mut remaining = max_line_size;
+    while !read_line(reader, &mut remaining)?
+        .ok_or(ParseError::UnexpectedEof)?
+        .is_empty()
+    {}
+    Ok(body)
+}
+
+/// Whether `c` is allowed in a method or header name (`tchar` in RFC 9110).
+fn is_token(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<Option<Request>, ParseError> {
+        Request::parse(&mut input.as_bytes())
+    }
+
+    #[test]
+    fn parse_request() {
+        let request = parse(
+            "POST /users?id=1&x=2 HTTP/1.1\r\nHost: localhost\r\ncontent-length: 5\r\n\r\nhello",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!("POST", request.method);
+        assert_eq!("/users", request.path);
+        assert_eq!(Some("id=1&x=2"), request.query.as_deref());
+        assert_eq!("HTTP/1.1", request.version);
+        assert_eq!(Some("localhost"), request.header("host"));
+        assert_eq!(b"hello", request.body.as_slice());
+
+        let request = parse("GET / HTTP/1.0\n\n").unwrap().unwrap();
+        assert_eq!("/", request.path);
+        assert!(request.query.is_none());
+        assert!(request.body.is_empty());
+
+        assert!(parse("").unwrap().is_none());
+
+        assert!(
+            parse("GET / HTTP/1.1\r\n\r\n")
+                .unwrap()
+                .unwrap()
+                .keep_alive()
+        );
+        assert!(
+            !parse("GET / HTTP/1.0\r\n\r\n")
+                .unwrap()
+                .unwrap()
+                .keep_alive()
+        );
+        assert!(
+            parse("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n")
+                .unwrap()
+                .unwrap()
+                .keep_alive()
+        );
+        assert!(
+            !parse("GET / HTTP/1.1\r\nConnection: upgrade, close\r\n\r\n")
+                .unwrap()
+                .unwrap()
+                .keep_alive()
+        );
+    }
+
+    #[test]
+    fn parse_chunked_body() {
+        let request = parse(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+             5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nTrailer: x\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(b"hello, world", request.body.as_slice());
+
+        for input in [
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nx\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n+1\r\na\r\n0\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nab\r\n0\r\n\r\n",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 1\r\n\r\n0\r\n\r\n",
+        ] {
+            let e = parse(input).unwrap_err();
+            assert_eq!(400, e.status(), "{:?}: {}", input, e);
+        }
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel"),
+            Err(ParseError::UnexpectedEof)
+        ));
+        assert_eq!(
+            501,
+            parse("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n")
+                .unwrap_err()
+                .status()
+        );
+    }
+
+    #[test]
+    fn reject_malformed_request() {
+        for input in [
+            "GET /\r\n\r\n",
+            "GET  / HTTP/1.1\r\n\r\n",
+            "GET index.html HTTP/1.1\r\n\r\n",
+            "GET / FTP/1.0\r\n\r\n",
+            "GET / HTTP/1.1\r\nNo colon\r\n\r\n",
+            "GET / HTTP/1.1\r\nBad Name: x\r\n\r\n",
+            "GET / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\nhello",
+            "GET / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\nab",
+        ] {
+            let e = parse(input).unwrap_err();
+            assert_eq!(400, e.status(), "{:?}: {}", input, e);
+        }
+        assert_eq!(505, parse("GET / HTTP/2.0\r\n\r\n").unwrap_err().status());
+        assert!(matches!(
+            parse("GET / HTTP/1.1\r\nHost: x\r\n"),
+            Err(ParseError::UnexpectedEof)
+        ));
+        assert!(matches!(
+            parse("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort"),
+            Err(ParseError::UnexpectedEof)
+        ));
+    }
+
+    #[test]
+    fn limit_head() {
+        let parse_head = |input: &str, max_size, max_headers| {
+            Request::parse_head(&mut input.as_bytes(), max_size, max_headers)
+        };
+        let input = "GET /users HTTP/1.1\r\nHost: x\r\nAccept: */*\r\n\r\n";
+        assert!(parse_head(input, input.len(), 2).unwrap().is_some());
+        assert_eq!(
+            431,
+            parse_head(input, input.len() - 1, 2).unwrap_err().status()
+        );
+        assert_eq!(431, parse_head(input, input.len(), 1).unwrap_err().status());
+        assert_eq!(414, parse_head(input, 10, 2).unwrap_err().status());
+
+        let e = ParseError::from(io::Error::from(io::ErrorKind::WouldBlock));
+        assert_eq!(408, e.status());
+    }
+
+    #[test]
+    fn limit_body() {
+        let read_body = |input: &str, max_size| {
+            let mut reader = input.as_bytes();
+            let mut request = Request::parse_head(&mut reader, MAX_HEADER_SIZE, MAX_HEADERS)
+                .unwrap()
+                .unwrap();
+            request
+                .read_body(&mut reader, MAX_HEADER_SIZE, max_size)
+                .map(|_| request.body)
+        };
+        let input = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(b"hello", read_body(input, 5).unwrap().as_slice());
+        assert_eq!(413, read_body(input, 4).unwrap_err().status());
+        // Rejected before reading or allocating anything
+        let input = "POST / HTTP/1.1\r\nContent-Length: 99999999999999\r\n\r\n";
+        assert_eq!(413, read_body(input, MAX_BODY_SIZE).unwrap_err().status());
+        // The chunks count together
+        let input = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                     3\r\nhel\r\n2\r\nlo\r\n0\r\n\r\n";
+        assert_eq!(b"hello", read_body(input, 5).unwrap().as_slice());
+        assert_eq!(413, read_body(input, 4).unwrap_err().status());
+        let input = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nffffffffffff\r\n";
+        assert_eq!(413, read_body(input, MAX_BODY_SIZE).unwrap_err().status());
+
+        // Only each line around a chunk counts towards the line limit
+        let input = format!(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n{}0\r\n\r\n",
+            "1\r\na\r\n".repeat(5000)
+        );
+        assert_eq!(5000, read_body(&input, MAX_BODY_SIZE).unwrap().len());
+        let input = format!(
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1;{}\r\na\r\n0\r\n\r\n",
+            "x".repeat(MAX_HEADER_SIZE)
+        );
+        assert_eq!(431, read_body(&input, MAX_BODY_SIZE).unwrap_err().status());
+
+        let input = "POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel";
+        assert!(matches!(
+            read_body(input, 5),
+            Err(ParseError::UnexpectedEof)
+        ));
+    }
+}
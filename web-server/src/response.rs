@@ -0,0 +1,339 @@
+// Copyright 2025 Yunze Xu
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    fmt,
+    io::{self, BufWriter, Write},
+};
+
+/// An HTTP response.
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Body,
+}
+
+/// A function that writes a body when the response is sent.
+pub type StreamFn = Box<dyn FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static>;
+
+/// The body of a [`Response`], either held in memory or streamed.
+pub enum Body {
+    Bytes(Vec<u8>),
+    /// Sent with `Transfer-Encoding: chunked`, unless the response has a `Content-Length` that
+    /// the function writes exactly.
+    Stream(StreamFn),
+}
+
+impl Body {
+    /// Returns the content of an in-memory body.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Body::Bytes(bytes) => Some(bytes),
+            Body::Stream(_) => None,
+        }
+    }
+
+    /// Collects the whole body into memory, running the stream function if any.
+    pub fn into_bytes(self) -> io::Result<Vec<u8>> {
+        match self {
+            Body::Bytes(bytes) => Ok(bytes),
+            Body::Stream(stream) => {
+                let mut bytes = vec![];
+                stream(&mut bytes)?;
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Body::Bytes(vec![])
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Body::Bytes(bytes) => f.debug_tuple("Bytes").field(&bytes.len()).finish(),
+            Body::Stream(_) => f.write_str("Stream(..)"),
+        }
+    }
+}
+
+impl Response {
+    pub fn new(status: u16) -> Self {
+        Response {
+            status,
+            headers: vec![],
+            body: Body::default(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: impl Into<String>) -> Self {
+        self.set_header(name, value);
+        self
+    }
+
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = Body::Bytes(body.into());
+        self
+    }
+
+    /// Sets a body that `stream` writes when the response is sent, e.g.
+    ///
+    /// ```
+    /// use std::io::Write;
+    /// use web_server::response::Response;
+    ///
+    /// let response = Response::new(200).with_stream(|writer| {
+    ///     for i in 0..3 {
+    ///         writeln!(writer, "line {}", i)?;
+    ///     }
+    ///     Ok(())
+    /// });
+    /// ```
+    pub fn with_stream<F>(mut self, stream: F) -> Self
+    where
+        F: FnOnce(&mut dyn Write) -> io::Result<()> + Send + 'static,
+    {
+        self.body = Body::Stream(Box::new(stream));
+        self
+    }
+
+    /// Returns the value of the first header named `name`, ignoring ASCII case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Replaces all headers named `name` with a single one.
+    pub fn set_header(&mut self, name: &str, value: impl Into<String>) {
+        self.headers
+            .retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.into()));
+    }
+
+    /// Drops the body but keeps the headers that describe it, for a response to `HEAD`.
+    pub fn strip_body(&mut self) {
+        if self.header("Content-Length").is_none() && self.header("Transfer-Encoding").is_none() {
+            match &self.body {
+                Body::Bytes(bytes) => self.set_header("Content-Length", bytes.len().to_string()),
+                Body::Stream(_) => self.set_header("Transfer-Encoding", "chunked"),
+            }
+        }
+        self.body = Body::default();
+    }
+
+    /// Writes the status line, the headers plus `Content-Length` or `Transfer-Encoding`, and the
+    /// body.
+    pub fn write_to<W: Write>(mut self, writer: &mut W) -> io::Result<()> {
+        // These responses never have a body, see RFC 9110 8.6, even if middleware added one
+        let bodiless = matches!(self.status, 100..=199 | 204 | 304);
+        if bodiless {
+            self.body = Body::default();
+            // A 304 may still tell the length of the representation it stands for
+            if self.status != 304 {
+                self.headers.retain(|(name, _)| {
+                    !name.eq_ignore_ascii_case("Content-Length")
+                        && !name.eq_ignore_ascii_case("Transfer-Encoding")
+                });
+            }
+        }
+        let framed = bodiless
+            || self.header("Content-Length").is_some()
+            || self.header("Transfer-Encoding").is_some();
+        let chunked = match &self.body {
+            Body::Stream(_) if !framed => {
+                self.headers
+                    .push(("Transfer-Encoding".to_string(), "chunked".to_string()));
+                true
+            }
+            Body::Bytes(bytes) if !framed => {
+                self.headers
+                    .push(("Content-Length".to_string(), bytes.len().to_string()));
+                false
+            }
+            _ => false,
+        };
+        let mut head = format!(
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        head.push_str("\r\n");
+        writer.write_all(head.as_bytes())?;
+        match self.body {
+            Body::Bytes(bytes) => writer.write_all(&bytes)?,
+            Body::Stream(stream) if chunked => {
+                let mut chunked = ChunkedWriter {
+                    inner: &mut *writer,
+                };
+                // Buffer small writes so that they don't become tiny chunks
+                let mut buffered = BufWriter::new(&mut chunked);
+                stream(&mut buffered)?;
+                buffered.flush()?;
+                drop(buffered);
+                chunked.finish()?;
+            }
+            Body::Stream(stream) => stream(writer)?,
+        }
+        writer.flush()
+    }
+}
+
+/// Writes each buffer as a chunk of `Transfer-Encoding: chunked`.
+struct ChunkedWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    /// Writes the last chunk, which ends the body.
+    fn finish(&mut self) -> io::Result<()> {
+        self.inner.write_all(b"0\r\n\r\n")
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        // An empty chunk would end the body
+        if buf.is_empty() {
+            return Ok(());
+        }
+        self.inner
+            .write_all(format!("{:x}\r\n", buf.len()).as_bytes())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        416 => "Range Not Satisfiable",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "Unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(response: Response) -> String {
+        let mut output = vec![];
+        response.write_to(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn write_response() {
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello",
+            write(
+                Response::new(200)
+                    .with_header("Content-Type", "text/plain")
+                    .with_body("hello")
+            )
+        );
+
+        assert_eq!(
+            "HTTP/1.1 304 Not Modified\r\nETag: \"x\"\r\n\r\n",
+            write(Response::new(304).with_header("ETag", "\"x\""))
+        );
+        // Bodies added by middleware are dropped
+        assert_eq!(
+            "HTTP/1.1 304 Not Modified\r\n\r\n",
+            write(Response::new(304).with_body("not modified"))
+        );
+        assert_eq!(
+            "HTTP/1.1 204 No Content\r\n\r\n",
+            write(
+                Response::new(204)
+                    .with_header("Content-Length", "7")
+                    .with_body("content")
+            )
+        );
+
+        let mut response = Response::new(200).with_body("hello");
+        response.strip_body();
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n",
+            write(response)
+        );
+    }
+
+    #[test]
+    fn write_stream() {
+        let response = Response::new(200).with_stream(|writer| {
+            writer.write_all(b"hello, ")?;
+            writer.flush()?;
+            writer.write_all(b"")?;
+            writer.write_all(b"world")
+        });
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n7\r\nhello, \r\n5\r\nworld\r\n0\r\n\r\n",
+            write(response)
+        );
+
+        let response = Response::new(200)
+            .with_header("Content-Length", "5")
+            .with_stream(|writer| writer.write_all(b"hello"));
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello",
+            write(response)
+        );
+
+        let mut response = Response::new(200).with_stream(|_| unreachable!());
+        response.strip_body();
+        assert_eq!(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n",
+            write(response)
+        );
+    }
+}
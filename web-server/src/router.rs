@@ -0,0 +1,385 @@
+// Copyright 2025 Yunze Xu
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    log,
+    middleware::{self, Middleware},
+    request::Request,
+    response::Response,
+    thread_pool::{Priority, panic_message},
+};
+use std::panic::{self, AssertUnwindSafe};
+
+pub type Handler = Box<dyn Fn(&Request) -> Response + Send + Sync + 'static>;
+
+/// Dispatches requests to handlers by method and path pattern.
+///
+/// A pattern is split by `/` into segments, each of which is either:
+/// - a literal that must match exactly, e.g. `users`
+/// - a parameter like `:id` that matches any single non-empty segment
+/// - a wildcard like `*path` (or just `*`) that matches the rest of the path, only as the last
+///   segment
+///
+/// Captured values are available from [`Request::param`]. Routes are tried in the order they
+/// were added and the first match wins.
+///
+/// [`Middleware`] added by [`Router::wrap`] runs around every request, including those answered
+/// with 404 or 405, while [`Router::wrap_route`] only wraps a single route.
+pub struct Router {
+    routes: Vec<Route>,
+    not_found: Handler,
+    high_priority: Vec<Vec<Segment>>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+struct Route {
+    method: String,
+    pattern: String,
+    segments: Vec<Segment>,
+    handler: Handler,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard(String),
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router {
+            routes: vec![],
+            not_found: Box::new(|_| Response::new(404)),
+            high_priority: vec![],
+            middleware: vec![],
+        }
+    }
+
+    /// Adds a route for `method` requests whose path matches `pattern`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `pattern` doesn't start with `/` or has a wildcard before its last segment.
+    pub fn route<F>(&mut self, method: &str, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.routes.push(Route {
+            method: method.to_ascii_uppercase(),
+            pattern: pattern.to_string(),
+            segments: parse_pattern(pattern),
+            handler: Box::new(handler),
+            middleware: vec![],
+        });
+        self
+    }
+
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route("GET", pattern, handler)
+    }
+
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.route("POST", pattern, handler)
+    }
+
+    /// Sets the handler for requests that match no route, which responds 404 by default.
+    pub fn not_found<F>(&mut self, handler: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Response + Send + Sync + 'static,
+    {
+        self.not_found = Box::new(handler);
+        self
+    }
+
+    /// Adds middleware that runs around all requests, after the middleware added before.
+    pub fn wrap<M: Middleware>(&mut self, middleware: M) -> &mut Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Adds middleware that only runs around the route added last, inside the middleware of
+    /// [`Router::wrap`], e.g.
+    ///
+    /// ```
+    /// use web_server::{middleware::SecurityHeaders, response::Response, router::Router};
+    ///
+    /// let mut router = Router::new();
+    /// router
+    ///     .get("/", |_| Response::new(200))
+    ///     .get("/api/users", |_| Response::new(200).with_body("[]"))
+    ///     .wrap_route(SecurityHeaders::new().with_header("Cache-Control", "no-store"));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if no route has been added.
+    pub fn wrap_route<M: Middleware>(&mut self, middleware: M) -> &mut Self {
+        let Some(route) = self.routes.last_mut() else {
+            panic!("wrap_route is called before any route is added");
+        };
+        route.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Marks the requests whose path matches `pattern` as [`Priority::High`], e.g. health checks
+    /// that shouldn't wait behind slow requests.
+    pub fn high_priority(&mut self, pattern: &str) -> &mut Self {
+        self.high_priority.push(parse_pattern(pattern));
+        self
+    }
+
+    /// Returns the priority of requests for `path`, see [`Router::high_priority`].
+    pub fn priority(&self, path: &str) -> Priority {
+        if self
+            .high_priority
+            .iter()
+            .any(|segments| match_segments(segments, path).is_some())
+        {
+            Priority::High
+        } else {
+            Priority::Normal
+        }
+    }
+
+    /// Runs the handler of the first matching route and stores the captured parameters in
+    /// `request.params` and its pattern in `request.route`.
+    ///
+    /// A `HEAD` request is served by a `GET` route, the server drops the body when writing the
+    /// response. If the path matches but the method doesn't, it responds
+    /// `405 Method Not Allowed` with an `Allow` header. A panicking handler is answered with
+    /// `500 Internal Server Error`, which the middleware still sees.
+    pub fn handle(&self, request: &mut Request) -> Response {
+        middleware::run(&self.middleware, request, |request| self.dispatch(request))
+    }
+
+    fn dispatch(&self, request: &mut Request) -> Response {
+        let head = request.method == "HEAD";
+        let mut allowed: Vec<&str> = vec![];
+        for route in &self.routes {
+            let Some(params) = match_segments(&route.segments, &request.path) else {
+                continue;
+            };
+            if route.method == request.method || (head && route.method == "GET") {
+                request.params = params;
+                request.route = Some(route.pattern.clone());
+                return middleware::run(&route.middleware, request, |request| {
+                    call(&route.handler, request)
+                });
+            }
+            allowed.push(&route.method);
+            if route.method == "GET" {
+                allowed.push("HEAD");
+            }
+        }
+        if allowed.is_empty() {
+            return call(&self.not_found, request);
+        }
+        let mut allow: Vec<&str> = vec![];
+        for method in allowed {
+            if !allow.contains(&method) {
+                allow.push(method);
+            }
+        }
+        Response::new(405).with_header("Allow", allow.join(", "))
+    }
+}
+
+fn call(handler: &Handler, request: &Request) -> Response {
+    // A panicking handler only fails its own request, the connection might be in an unknown
+    // state though, so close it
+    match panic::catch_unwind(AssertUnwindSafe(|| handler(request))) {
+        Ok(response) => response,
+        Err(payload) => {
+            log!(
+                "Handler of {} {} panicked: {}",
+                request.method,
+                request.path,
+                panic_message(payload.as_ref())
+            );
+            Response::new(500).with_header("Connection", "close")
+        }
+    }
+}
+
+/// Returns the captured parameters if `path` matches the pattern.
+fn match_segments(segments: &[Segment], path: &str) -> Option<Vec<(String, String)>> {
+    let mut params = vec![];
+    let mut parts = path.strip_prefix('/')?.split('/');
+    for segment in segments {
+        match segment {
+            Segment::Wildcard(name) => {
+                let rest = parts.collect::<Vec<_>>().join("/");
+                params.push((name.clone(), rest));
+                return Some(params);
+            }
+            Segment::Literal(literal) => {
+                if parts.next()? != literal {
+                    return None;
+                }
+            }
+            Segment::Param(name) => match parts.next()? {
+                "" => return None,
+                value => params.push((name.clone(), value.to_string())),
+            },
+        }
+    }
+    match parts.next() {
+        None => Some(params),
+        _ => None,
+    }
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    let Some(pattern) = pattern.strip_prefix('/') else {
+        panic!("route pattern {:?} doesn't start with '/'", pattern);
+    };
+    let parts = pattern.split('/').collect::<Vec<_>>();
+    parts
+        .iter()
+        .enumerate()
+        .map(|(i, part)| {
+            if let Some(name) = part.strip_prefix(':') {
+                Segment::Param(name.to_string())
+            } else if let Some(name) = part.strip_prefix('*') {
+                if i + 1 != parts.len() {
+                    panic!(
+                        "wildcard in route pattern {:?} isn't the last segment",
+                        pattern
+                    );
+                }
+                Segment::Wildcard(if name.is_empty() { "*" } else { name }.to_string())
+            } else {
+                Segment::Literal(part.to_string())
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str) -> Request {
+        let raw = format!("{} {} HTTP/1.1\r\n\r\n", method, path);
+        Request::parse(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn body(response: &Response) -> &str {
+        std::str::from_utf8(response.body.as_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn dispatch() {
+        let mut router = Router::new();
+        router
+            .get("/", |_| Response::new(200).with_body("index"))
+            .get("/users/:id", |req| {
+                Response::new(200).with_body(format!("user {}", req.param("id").unwrap()))
+            })
+            .post("/users/:id", |_| Response::new(201))
+            .get("/files/*path", |req| {
+                Response::new(200).with_body(req.param("path").unwrap().to_string())
+            });
+
+        assert_eq!("index", body(&router.handle(&mut request("GET", "/"))));
+        assert_eq!(
+            "user 42",
+            body(&router.handle(&mut request("GET", "/users/42")))
+        );
+        let mut post = request("POST", "/users/42");
+        assert_eq!(201, router.handle(&mut post).status);
+        assert_eq!(Some("/users/:id"), post.route.as_deref());
+        assert_eq!(
+            "a/b.txt",
+            body(&router.handle(&mut request("GET", "/files/a/b.txt")))
+        );
+        let mut missing = request("GET", "/users/");
+        assert_eq!(404, router.handle(&mut missing).status);
+        assert_eq!(None, missing.route);
+        assert_eq!(
+            404,
+            router.handle(&mut request("GET", "/users/42/x")).status
+        );
+
+        assert_eq!("index", body(&router.handle(&mut request("HEAD", "/"))));
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        let mut router = Router::new();
+        router
+            .get("/users/:id", |_| Response::new(200))
+            .route("DELETE", "/users/:id", |_| Response::new(204))
+            .post("/users/*", |_| Response::new(201));
+
+        let response = router.handle(&mut request("PUT", "/users/1"));
+        assert_eq!(405, response.status);
+        assert_eq!(Some("GET, HEAD, DELETE, POST"), response.header("Allow"));
+    }
+
+    #[test]
+    fn middleware() {
+        use crate::middleware::{Cors, RequestId, SecurityHeaders};
+
+        let mut router = Router::new();
+        router
+            .wrap(RequestId::new())
+            .wrap(Cors::new())
+            .get("/", |_| Response::new(200))
+            .get("/panic", |_| panic!("oops"))
+            .wrap_route(SecurityHeaders::new());
+
+        let response = router.handle(&mut request("GET", "/"));
+        assert!(response.header("X-Request-Id").is_some());
+        assert_eq!(None, response.header("X-Frame-Options"));
+
+        let response = router.handle(&mut request("GET", "/panic"));
+        assert_eq!(500, response.status);
+        assert!(response.header("X-Request-Id").is_some());
+        assert_eq!(Some("DENY"), response.header("X-Frame-Options"));
+
+        let response = router.handle(&mut request("GET", "/missing"));
+        assert_eq!(404, response.status);
+        assert!(response.header("X-Request-Id").is_some());
+
+        let raw = "OPTIONS / HTTP/1.1\r\nOrigin: https://a.com\r\n\
+                   Access-Control-Request-Method: GET\r\n\r\n";
+        let response = router.handle(&mut Request::parse(&mut raw.as_bytes()).unwrap().unwrap());
+        assert_eq!(204, response.status);
+        assert_eq!(Some("*"), response.header("Access-Control-Allow-Origin"));
+    }
+
+    #[test]
+    fn priority() {
+        let mut router = Router::new();
+        router.high_priority("/health").high_priority("/admin/*");
+        assert_eq!(Priority::High, router.priority("/health"));
+        assert_eq!(Priority::High, router.priority("/admin/users/1"));
+        assert_eq!(Priority::Normal, router.priority("/health/x"));
+        assert_eq!(Priority::Normal, router.priority("/"));
+    }
+}
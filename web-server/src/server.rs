@@ -0,0 +1,1675 @@
+// Copyright 2025 Yunze Xu
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    log,
+    metrics::{ConnectionGuard, Metrics},
+    request::{self, ParseError, Request},
+    response::{Body, Response},
+    router::Router,
+    thread_pool::{Priority, ThreadPool, panic_message},
+    tls::TlsConfig,
+};
+use mio::{Events, Interest, Poll, Registry, Token, Waker, net::TcpListener};
+use rustls::{ServerConnection, StreamOwned};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    mem,
+    net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs},
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const REDIRECT: Token = Token(2);
+// The tokens of the connections waiting for their first request start from here
+const FIRST_CONNECTION: usize = 3;
+// How long to stop accepting after a failure like running out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// How many reads of 8 KiB drain the input of a rejected connection at most
+const REJECT_DRAIN_READS: usize = 8;
+
+/// Accepts connections and handles them with a [`Router`] on a [`ThreadPool`].
+///
+/// The acceptor blocks in [`Poll::poll`] until either a connection arrives or a
+/// [`ShutdownHandle`] wakes it up, so it stays idle without connections and exits immediately.
+/// A connection is only handed to the thread pool once a request arrives, with the priority that
+/// the router assigns to the request path, see [`Router::high_priority`]. Between requests, a
+/// kept-alive connection goes back to the acceptor, so idle clients don't hold workers.
+///
+/// With [`Server::with_tls`], the listener serves HTTPS instead, and
+/// [`Server::with_https_redirect`] adds a plain HTTP listener that redirects to it. The request
+/// of an HTTPS connection can't be peeked before the handshake, so it always has the normal
+/// priority.
+pub struct Server {
+    listener: TcpListener,
+    redirect: Option<TcpListener>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    poll: Poll,
+    waker: Arc<Waker>,
+    router: Router,
+    config: ServerConfig,
+    pool: ThreadPool,
+    metrics: Option<Metrics>,
+    running: Arc<AtomicBool>,
+    reloads: Arc<Mutex<Vec<Reload>>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// How long a connection may stay idle waiting for the next request, or for the first one.
+    pub keep_alive_timeout: Duration,
+    /// How long a client may take to send the request line and headers once it started, the
+    /// request is answered with `408 Request Timeout` after that.
+    pub header_read_timeout: Duration,
+    /// How long a read of the request body may wait for data.
+    pub body_read_timeout: Duration,
+    /// How long a write of the response may wait for the client to read.
+    pub write_timeout: Duration,
+    /// The limit on the bytes of the request line and headers, beyond which the request is
+    /// answered with `431 Request Header Fields Too Large`, or `414 URI Too Long` if the request
+    /// line alone exceeds it.
+    pub max_header_size: usize,
+    /// The limit on the number of header fields, beyond which the request is answered with
+    /// `431 Request Header Fields Too Large`.
+    pub max_headers: usize,
+    /// The limit on the bytes of the request body, beyond which the request is answered with
+    /// `413 Content Too Large`.
+    pub max_body_size: usize,
+    /// How long the shutdown waits for queued and in-flight requests before closing their
+    /// connections.
+    pub shutdown_timeout: Duration,
+    /// The `Retry-After` of the `503 Service Unavailable` response sent when the queues of the
+    /// thread pool are full, in seconds.
+    pub retry_after: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            keep_alive_timeout: Duration::from_secs(5),
+            header_read_timeout: Duration::from_secs(10),
+            body_read_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(30),
+            max_header_size: request::MAX_HEADER_SIZE,
+            max_headers: request::MAX_HEADERS,
+            max_body_size: request::MAX_BODY_SIZE,
+            shutdown_timeout: Duration::from_secs(10),
+            retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+/// The state shared by the acceptor and the connection handlers.
+struct Shared {
+    current: RwLock<Arc<Current>>,
+    // The port to redirect plain HTTP requests to
+    https_port: u16,
+    metrics: Option<Metrics>,
+    running: Arc<AtomicBool>,
+    connections: Connections,
+    waker: Arc<Waker>,
+    // The kept-alive connections handed back to the acceptor by the workers
+    idle: Mutex<Vec<Waiting>>,
+}
+
+impl Shared {
+    fn current(&self) -> Arc<Current> {
+        self.current.read().unwrap().clone()
+    }
+}
+
+/// The settings that [`ReloadHandle::reload`] can replace. Each request uses the ones current
+/// when it arrives, and each connection keeps the certificates it started with.
+#[derive(Clone)]
+struct Current {
+    router: Arc<Router>,
+    config: ServerConfig,
+    tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+/// The open connections, tracked so that the shutdown can close them.
+#[derive(Default)]
+struct Connections {
+    next_id: AtomicU64,
+    // Each connection maps to a clone of its stream and whether it's waiting for a request
+    streams: Mutex<HashMap<u64, (TcpStream, bool)>>,
+}
+
+impl Connections {
+    fn add(&self, stream: &TcpStream) -> io::Result<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let stream = stream.try_clone()?;
+        self.streams.lock().unwrap().insert(id, (stream, true));
+        Ok(id)
+    }
+
+    fn set_idle(&self, id: u64, idle: bool) {
+        if let Some((_, state)) = self.streams.lock().unwrap().get_mut(&id) {
+            *state = idle;
+        }
+    }
+
+    fn remove(&self, id: u64) {
+        self.streams.lock().unwrap().remove(&id);
+    }
+
+    /// Stops reading from the connections waiting for a request, so that their handlers exit.
+    fn close_idle(&self) {
+        for (stream, idle) in self.streams.lock().unwrap().values() {
+            if *idle {
+                let _ = stream.shutdown(Shutdown::Read);
+            }
+        }
+    }
+
+    /// Closes all connections, returning how many there were.
+    fn close_all(&self) -> usize {
+        let streams = self.streams.lock().unwrap();
+        for (stream, _) in streams.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        streams.len()
+    }
+}
+
+/// Stops a running [`Server`], e.g. from a `Ctrl+C` handler.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    running: Arc<AtomicBool>,
+    waker: Arc<Waker>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::Release);
+        if let Err(e) = self.waker.wake() {
+            log!("Failed to wake up the acceptor: {}", e);
+        }
+    }
+}
+
+/// The settings replaced by [`ReloadHandle::reload`], the ones left out stay as they are.
+#[derive(Default)]
+pub struct Reload {
+    router: Option<Router>,
+    config: Option<ServerConfig>,
+    tls: Option<TlsConfig>,
+    workers: Option<usize>,
+}
+
+impl Reload {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_router(mut self, router: Router) -> Self {
+        self.router = Some(router);
+        self
+    }
+
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Replaces the certificates, which is ignored unless the server serves HTTPS.
+    pub fn with_tls(mut self, tls: &TlsConfig) -> Self {
+        self.tls = Some(tls.clone());
+        self
+    }
+
+    /// Changes the minimum number of workers of the thread pool, see
+    /// [`ThreadPool::set_min_workers`].
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = Some(workers);
+        self
+    }
+}
+
+/// Changes the settings of a running [`Server`], e.g. from a `SIGHUP` handler.
+#[derive(Clone)]
+pub struct ReloadHandle {
+    reloads: Arc<Mutex<Vec<Reload>>>,
+    waker: Arc<Waker>,
+}
+
+impl ReloadHandle {
+    /// Applies `reload` on the acceptor thread. The requests that have started finish with the
+    /// previous settings.
+    pub fn reload(&self, reload: Reload) {
+        self.reloads.lock().unwrap().push(reload);
+        if let Err(e) = self.waker.wake() {
+            log!("Failed to wake up the acceptor: {}", e);
+        }
+    }
+}
+
+impl Server {
+    pub fn bind<A: ToSocketAddrs>(addr: A, router: Router, pool: ThreadPool) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        let listener = listen(addr, poll.registry(), LISTENER)?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        Ok(Server {
+            listener,
+            redirect: None,
+            tls: None,
+            poll,
+            waker,
+            router,
+            config: ServerConfig::default(),
+            pool,
+            metrics: None,
+            running: Arc::new(AtomicBool::new(true)),
+            reloads: Arc::default(),
+        })
+    }
+
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Serves HTTPS with the certificates of `tls` instead of plain HTTP.
+    pub fn with_tls(mut self, tls: &TlsConfig) -> Self {
+        self.tls = Some(tls.server_config());
+        self
+    }
+
+    /// Listens on `addr` for plain HTTP requests as well, which are answered with
+    /// `308 Permanent Redirect` to the same URL on the HTTPS port.
+    pub fn with_https_redirect<A: ToSocketAddrs>(mut self, addr: A) -> io::Result<Self> {
+        self.redirect = Some(listen(addr, self.poll.registry(), REDIRECT)?);
+        Ok(self)
+    }
+
+    /// Reports the open connections and the stats of the thread pool to `metrics`, see
+    /// [`Metrics`].
+    pub fn with_metrics(mut self, metrics: &Metrics) -> Self {
+        metrics.set_thread_pool(self.pool.monitor());
+        self.metrics = Some(metrics.clone());
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// The address of the listener added by [`Server::with_https_redirect`].
+    pub fn redirect_addr(&self) -> Option<io::Result<SocketAddr>> {
+        self.redirect.as_ref().map(TcpListener::local_addr)
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            running: self.running.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+
+    pub fn reload_handle(&self) -> ReloadHandle {
+        ReloadHandle {
+            reloads: self.reloads.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+
+    /// Accepts connections on the "acceptor" thread until the server is shut down.
+    ///
+    /// Then it stops accepting and drains: the queued and in-flight requests are served with
+    /// `Connection: close` until [`ServerConfig::shutdown_timeout`] expires, after which the
+    /// remaining connections are closed.
+    pub fn run(self) {
+        let https_port = self.listener.local_addr().map_or(443, |addr| addr.port());
+        let Server {
+            listener,
+            redirect: redirect_listener,
+            tls,
+            mut poll,
+            waker,
+            router,
+            config,
+            mut pool,
+            metrics,
+            running,
+            reloads,
+            ..
+        } = self;
+        let shared = Arc::new(Shared {
+            current: RwLock::new(Arc::new(Current {
+                router: Arc::new(router),
+                config,
+                tls,
+            })),
+            https_port,
+            metrics,
+            running,
+            connections: Connections::default(),
+            waker,
+            idle: Mutex::default(),
+        });
+        let shared_clone = shared.clone();
+        let mut pool = thread::Builder::new()
+            .name(String::from("acceptor"))
+            .spawn(move || {
+                let mut events = Events::with_capacity(16);
+                let mut waiting = WaitingConnections::default();
+                let mut accept_retry = None;
+                loop {
+                    let timeout = waiting
+                        .next_deadline()
+                        .into_iter()
+                        .chain(accept_retry)
+                        .min()
+                        .map(|deadline| deadline.saturating_duration_since(Instant::now()));
+                    if let Err(e) = poll.poll(&mut events, timeout) {
+                        if e.kind() == io::ErrorKind::Interrupted {
+                            continue;
+                        }
+                        log!("Failed to poll the listener: {}", e);
+                        return pool;
+                    }
+                    if !shared_clone.running.load(Ordering::Acquire) {
+                        log!("Server has been shut down, exiting");
+                        waiting.close_all();
+                        return pool;
+                    }
+                    let mut accepting = vec![];
+                    for event in events.iter() {
+                        match event.token() {
+                            // The pending connections are accepted after the back-off
+                            token @ (LISTENER | REDIRECT) if accept_retry.is_none() => {
+                                accepting.push(token);
+                            }
+                            LISTENER | REDIRECT => {}
+                            WAKER => {
+                                let reloads = mem::take(&mut *reloads.lock().unwrap());
+                                for reload in reloads {
+                                    apply(reload, &mut pool, &shared_clone);
+                                }
+                                let idle = mem::take(&mut *shared_clone.idle.lock().unwrap());
+                                for connection in idle {
+                                    let addr = connection.addr;
+                                    if let Err(e) = waiting.add(poll.registry(), connection) {
+                                        log!("Failed to register connection from {}: {}", addr, e);
+                                    }
+                                }
+                            }
+                            token => {
+                                if let Some(connection) = waiting.remove(token) {
+                                    dispatch(connection, poll.registry(), &mut pool, &shared_clone);
+                                }
+                            }
+                        }
+                    }
+                    if accept_retry.is_some_and(|retry| retry <= Instant::now()) {
+                        accept_retry = None;
+                        accepting = vec![LISTENER, REDIRECT];
+                    }
+                    for token in accepting {
+                        let listener = match (token, &redirect_listener) {
+                            (REDIRECT, Some(redirect_listener)) => redirect_listener,
+                            (REDIRECT, None) => continue,
+                            _ => &listener,
+                        };
+                        if let Err(e) = accept(
+                            listener,
+                            token == REDIRECT,
+                            poll.registry(),
+                            &mut waiting,
+                            &shared_clone,
+                        ) {
+                            // The listeners are edge-triggered, so they have to be retried
+                            log!(
+                                "Failed to accept connection, retrying in {:?}: {}",
+                                ACCEPT_BACKOFF,
+                                e
+                            );
+                            accept_retry = Some(Instant::now() + ACCEPT_BACKOFF);
+                            break;
+                        }
+                    }
+                    waiting.close_expired();
+                }
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+
+        let timeout = shared.current().config.shutdown_timeout;
+        log!("Draining in-flight requests for up to {:?}", timeout);
+        shared.connections.close_idle();
+        let abandoned = pool.shutdown_timeout(timeout);
+        let closed = shared.connections.close_all();
+        if abandoned > 0 || closed > 0 {
+            log!(
+                "Abandoned {} queued and closed {} in-flight connections after the deadline",
+                abandoned,
+                closed
+            );
+        }
+    }
+}
+
+/// Replaces the current settings by those of `reload`.
+fn apply(reload: Reload, pool: &mut ThreadPool, shared: &Shared) {
+    let mut current = Current::clone(&shared.current());
+    if let Some(router) = reload.router {
+        current.router = Arc::new(router);
+    }
+    if let Some(config) = reload.config {
+        current.config = config;
+    }
+    match (reload.tls, &current.tls) {
+        (Some(tls), Some(_)) => current.tls = Some(tls.server_config()),
+        // The listener would have to switch protocols
+        (Some(_), None) => {
+            log!("Ignoring the certificates since the server serves plain HTTP");
+        }
+        (None, _) => {}
+    }
+    *shared.current.write().unwrap() = Arc::new(current);
+    if let Some(workers) = reload.workers {
+        pool.set_min_workers(workers);
+    }
+    log!("The server has been reloaded");
+}
+
+/// A connection waiting for a request, either its first one or the next one after keep-alive.
+struct Waiting {
+    stream: mio::net::TcpStream,
+    addr: SocketAddr,
+    // Whether it's accepted by the listener that redirects to HTTPS
+    redirect: bool,
+    // The session of a kept-alive HTTPS connection
+    tls: Option<ServerConnection>,
+    deadline: Instant,
+    // Counts it as open until the connection is closed
+    metrics: Option<ConnectionGuard>,
+}
+
+impl Waiting {
+    /// Closes the connection, after a `close_notify` alert if it's HTTPS.
+    fn close(mut self) {
+        if let Some(tls) = &mut self.tls {
+            tls.send_close_notify();
+            // The socket is non-blocking, so the alert is given up if it doesn't fit
+            while tls.wants_write() {
+                if tls.write_tls(&mut self.stream).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// The connections registered to the acceptor's [`Poll`] until a request arrives.
+struct WaitingConnections {
+    connections: HashMap<Token, Waiting>,
+    next_token: usize,
+}
+
+impl Default for WaitingConnections {
+    fn default() -> Self {
+        WaitingConnections {
+            connections: HashMap::new(),
+            next_token: FIRST_CONNECTION,
+        }
+    }
+}
+
+impl WaitingConnections {
+    fn add(&mut self, registry: &Registry, mut connection: Waiting) -> io::Result<()> {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        registry.register(&mut connection.stream, token, Interest::READABLE)?;
+        self.connections.insert(token, connection);
+        Ok(())
+    }
+
+    fn remove(&mut self, token: Token) -> Option<Waiting> {
+        self.connections.remove(&token)
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.connections
+            .values()
+            .map(|connection| connection.deadline)
+            .min()
+    }
+
+    /// Closes the connections that sent nothing within the keep-alive timeout.
+    fn close_expired(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .connections
+            .extract_if(|_, connection| connection.deadline <= now);
+        for (_, connection) in expired {
+            log!("Closing idle connection from {}", connection.addr);
+            connection.close();
+        }
+    }
+
+    fn close_all(&mut self) {
+        for (_, connection) in self.connections.drain() {
+            connection.close();
+        }
+    }
+}
+
+fn listen<A: ToSocketAddrs>(addr: A, registry: &Registry, token: Token) -> io::Result<TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener);
+    registry.register(&mut listener, token, Interest::READABLE)?;
+    Ok(listener)
+}
+
+/// Accepts the pending connections and waits for their first requests.
+fn accept(
+    listener: &TcpListener,
+    redirect: bool,
+    registry: &Registry,
+    waiting: &mut WaitingConnections,
+    shared: &Shared,
+) -> io::Result<()> {
+    // The listener is edge-triggered, so accept until it would block
+    loop {
+        match listener.accept() {
+            Ok((stream, addr)) => {
+                log!("Accepted connection from {}", addr);
+                let connection = Waiting {
+                    stream,
+                    addr,
+                    redirect,
+                    tls: None,
+                    deadline: Instant::now() + shared.current().config.keep_alive_timeout,
+                    metrics: shared.metrics.as_ref().map(Metrics::open_connection),
+                };
+                if let Err(e) = waiting.add(registry, connection) {
+                    log!("Failed to register connection from {}: {}", addr, e);
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(ref e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::Interrupted | io::ErrorKind::ConnectionAborted
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Hands a connection whose request has arrived to the thread pool, with the priority of the
+/// request path.
+fn dispatch(connection: Waiting, registry: &Registry, pool: &mut ThreadPool, shared: &Arc<Shared>) {
+    let Waiting {
+        mut stream,
+        addr,
+        redirect,
+        tls,
+        metrics,
+        ..
+    } = connection;
+    let current = shared.current();
+    let https = current.tls.is_some() && !redirect;
+    let _ = registry.deregister(&mut stream);
+    let mut buf = [0; 1024];
+    let priority = match stream.peek(&mut buf) {
+        // The client closed the connection without a request
+        Ok(0) => return,
+        // The request of an HTTPS connection is encrypted
+        Ok(_) if https => Priority::Normal,
+        Ok(n) => {
+            request_path(&buf[..n]).map_or(Priority::Normal, |path| current.router.priority(path))
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Priority::Normal,
+        Err(e) => {
+            log!("Failed to read request from {}: {}", addr, e);
+            return;
+        }
+    };
+    let stream = TcpStream::from(stream);
+    // Keep another handle to respond 503 if it can't be queued
+    let overflow = stream.try_clone();
+    let shared_clone = shared.clone();
+    let job = move || handle_connection(stream, addr, redirect, tls, metrics, &shared_clone);
+    if pool.try_execute_with_priority(priority, job).is_err() {
+        log!(
+            "Rejected connection from {} since the thread pool is full",
+            addr
+        );
+        // A plain response would break the handshake, so the HTTPS connection is just closed
+        match overflow {
+            Ok(stream) if https => {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            Ok(stream) => reject(stream, &current.config),
+            Err(_) => {}
+        }
+    }
+}
+
+/// Returns the path of the request line at the start of `bytes`, if the target is complete.
+fn request_path(bytes: &[u8]) -> Option<&str> {
+    let mut parts = bytes.splitn(3, |&c| c == b' ');
+    let (_, target, _) = (parts.next()?, parts.next()?, parts.next()?);
+    let target = std::str::from_utf8(target).ok()?;
+    target.split('?').next()
+}
+
+/// Responds `503 Service Unavailable` to a connection that the thread pool can't take.
+fn reject(mut stream: TcpStream, config: &ServerConfig) {
+    let response = Response::new(503)
+        .with_header("Retry-After", config.retry_after.as_secs().to_string())
+        .with_header("Connection", "close");
+    // The send buffer of a new connection has room for the response, so it doesn't block
+    if let Err(e) = response.write_to(&mut stream) {
+        log!("Failed to write response: {}", e);
+    }
+    let _ = stream.shutdown(Shutdown::Write);
+    // Closing a socket with unread input resets the connection, which can discard the response
+    // before the client reads it. The input that has arrived is drained without blocking the
+    // acceptor, up to a bound in case the client keeps sending.
+    let _ = stream.set_nonblocking(true);
+    let mut buf = [0; 8192];
+    for _ in 0..REJECT_DRAIN_READS {
+        match stream.read(&mut buf) {
+            Ok(n) if n > 0 => {}
+            _ => break,
+        }
+    }
+}
+
+/// Serves requests from `stream` in order until either side closes the connection, or hands it
+/// back to the acceptor once it's kept alive without a pending request.
+fn handle_connection(
+    stream: TcpStream,
+    addr: SocketAddr,
+    redirect: bool,
+    tls: Option<ServerConnection>,
+    metrics: Option<ConnectionGuard>,
+    shared: &Shared,
+) {
+    let current = shared.current();
+    // Accepted sockets inherit the non-blocking flag of the listener on some platforms, and the
+    // acceptor polls kept-alive connections without blocking
+    let id = match stream
+        .set_nonblocking(false)
+        .and_then(|_| stream.set_write_timeout(Some(current.config.write_timeout)))
+        .and_then(|_| shared.connections.add(&stream))
+    {
+        Ok(id) => id,
+        Err(e) => {
+            log!("Failed to configure the connection: {}", e);
+            return;
+        }
+    };
+    let deadline = Cell::new(None);
+    let timed_stream = TimedStream {
+        stream: &stream,
+        deadline: &deadline,
+        read_timeout: current.config.body_read_timeout,
+    };
+    let (idle, tls) = match &current.tls {
+        _ if redirect => {
+            redirect_to_https(timed_stream, shared);
+            (false, None)
+        }
+        Some(config) => {
+            let connection = match tls {
+                Some(connection) => Ok(connection),
+                None => ServerConnection::new(config.clone()),
+            };
+            match connection {
+                Ok(connection) => {
+                    // The handshake happens on the first read, within the keep-alive timeout
+                    let mut tls_stream = StreamOwned::new(connection, timed_stream);
+                    let idle = loop {
+                        let idle = serve_requests(
+                            UncleanEof(&mut tls_stream),
+                            Some(addr),
+                            id,
+                            &deadline,
+                            shared,
+                        );
+                        // The next request might have been decrypted along with the last one
+                        if !idle
+                            || !tls_stream
+                                .conn
+                                .process_new_packets()
+                                .is_ok_and(|state| state.plaintext_bytes_to_read() > 0)
+                        {
+                            break idle;
+                        }
+                    };
+                    if !idle {
+                        tls_stream.conn.send_close_notify();
+                        // `flush` would wait for the handshake if it failed, so write the alert
+                        // directly
+                        while tls_stream.conn.wants_write() {
+                            if tls_stream.conn.write_tls(&mut tls_stream.sock).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    (idle, Some(tls_stream.conn))
+                }
+                Err(e) => {
+                    log!("Failed to create the TLS connection: {}", e);
+                    (false, None)
+                }
+            }
+        }
+        None => (
+            serve_requests(timed_stream, Some(addr), id, &deadline, shared),
+            None,
+        ),
+    };
+    shared.connections.remove(id);
+    if idle {
+        park(stream, addr, tls, metrics, shared);
+    }
+}
+
+/// Hands a kept-alive connection back to the acceptor until its next request arrives.
+fn park(
+    stream: TcpStream,
+    addr: SocketAddr,
+    tls: Option<ServerConnection>,
+    metrics: Option<ConnectionGuard>,
+    shared: &Shared,
+) {
+    if let Err(e) = stream.set_nonblocking(true) {
+        log!("Failed to configure the connection: {}", e);
+        return;
+    }
+    let connection = Waiting {
+        stream: mio::net::TcpStream::from_std(stream),
+        addr,
+        redirect: false,
+        tls,
+        deadline: Instant::now() + shared.current().config.keep_alive_timeout,
+        metrics,
+    };
+    shared.idle.lock().unwrap().push(connection);
+    if let Err(e) = shared.waker.wake() {
+        log!("Failed to wake up the acceptor: {}", e);
+    }
+}
+
+/// A connection whose reads wait until the deadline if one is set, or for the read timeout
+/// otherwise. Timed out reads fail with [`io::ErrorKind::TimedOut`].
+#[derive(Clone, Copy)]
+struct TimedStream<'a> {
+    stream: &'a TcpStream,
+    deadline: &'a Cell<Option<Instant>>,
+    read_timeout: Duration,
+}
+
+impl Read for TimedStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let timeout = match self.deadline.get() {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => self.read_timeout,
+        };
+        if timeout.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(timeout))?;
+        // Unix reports an expired timeout as `WouldBlock`
+        self.stream.read(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock => io::ErrorKind::TimedOut.into(),
+            _ => e,
+        })
+    }
+}
+
+impl Write for TimedStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Treats a TLS connection closed without `close_notify` like a clean close, which is what most
+/// clients do. A truncated request is detected by its framing anyway.
+struct UncleanEof<S>(S);
+
+impl<S: Read> Read for UncleanEof<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl<S: Write> Write for UncleanEof<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Answers a plain HTTP request with a redirect to the same URL on the HTTPS listener.
+fn redirect_to_https(mut stream: TimedStream, shared: &Shared) {
+    let current = shared.current();
+    let config = &current.config;
+    stream
+        .deadline
+        .set(Some(Instant::now() + config.header_read_timeout));
+    let request = Request::parse_head(
+        &mut BufReader::new(stream),
+        config.max_header_size,
+        config.max_headers,
+    );
+    let request = match request {
+        Ok(Some(request)) => request,
+        Ok(None) | Err(ParseError::Io(_)) => return,
+        Err(e) => {
+            let _ = Response::new(e.status())
+                .with_header("Connection", "close")
+                .write_to(&mut stream);
+            return;
+        }
+    };
+    let response = match request.header("Host") {
+        Some(host) => {
+            // Replace the port of the plain listener, IPv6 addresses are in brackets
+            let host = match host.rsplit_once(':') {
+                Some((name, port)) if !port.contains(']') => name,
+                _ => host,
+            };
+            let port = match shared.https_port {
+                443 => String::new(),
+                port => format!(":{}", port),
+            };
+            let target = match &request.query {
+                Some(query) => format!("{}?{}", request.path, query),
+                None => request.path.clone(),
+            };
+            Response::new(308)
+                .with_header("Location", format!("https://{}{}{}", host, port, target))
+        }
+        None => Response::new(400),
+    };
+    if let Err(e) = response
+        .with_header("Connection", "close")
+        .write_to(&mut stream)
+    {
+        log!("Failed to write response: {}", e);
+    }
+}
+
+/// Serves the requests from `stream` in order, returning whether the connection is kept alive
+/// without a pending request.
+fn serve_requests<S: Read + Write>(
+    stream: S,
+    remote_addr: Option<SocketAddr>,
+    id: u64,
+    deadline: &Cell<Option<Instant>>,
+    shared: &Shared,
+) -> bool {
+    // Pipelined requests are buffered in the reader and answered one by one
+    let mut buf_reader = BufReader::new(stream);
+    loop {
+        // A reload applies from the next request on
+        let current = shared.current();
+        let config = &current.config;
+        shared.connections.set_idle(id, true);
+        deadline.set(Some(Instant::now() + config.keep_alive_timeout));
+        match buf_reader.fill_buf() {
+            Ok([]) => return false,
+            Ok(_) => {}
+            Err(e) => {
+                if !request::is_timeout(&e) {
+                    log!("Failed to read request: {}", e);
+                }
+                return false;
+            }
+        }
+        shared.connections.set_idle(id, false);
+        // The headers must arrive in time as a whole, while the body only must not stall
+        deadline.set(Some(Instant::now() + config.header_read_timeout));
+        let request =
+            Request::parse_head(&mut buf_reader, config.max_header_size, config.max_headers)
+                .and_then(|request| {
+                    deadline.set(None);
+                    match request {
+                        Some(mut request) => request
+                            .read_body(
+                                &mut buf_reader,
+                                config.max_header_size,
+                                config.max_body_size,
+                            )
+                            .map(|_| Some(request)),
+                        None => Ok(None),
+                    }
+                });
+        let (mut response, mut keep_alive, http_1_0) = match request {
+            Ok(Some(mut request)) => {
+                request.remote_addr = remote_addr;
+                // The router catches panics of handlers, this is for the ones of middleware
+                let mut response = match panic::catch_unwind(AssertUnwindSafe(|| {
+                    current.router.handle(&mut request)
+                })) {
+                    Ok(response) => response,
+                    Err(payload) => {
+                        log!(
+                            "Middleware of {} {} panicked: {}",
+                            request.method,
+                            request.path,
+                            panic_message(payload.as_ref())
+                        );
+                        Response::new(500).with_header("Connection", "close")
+                    }
+                };
+                if request.method == "HEAD" {
+                    response.strip_body();
+                } else if request.version == "HTTP/1.0"
+                    && matches!(response.body, Body::Stream(_))
+                    && response.header("Content-Length").is_none()
+                {
+                    // HTTP/1.0 has no chunked encoding, so collect the body to send its length
+                    response = match mem::take(&mut response.body).into_bytes() {
+                        Ok(bytes) => Response {
+                            body: Body::Bytes(bytes),
+                            ..response
+                        },
+                        Err(e) => {
+                            log!("Failed to generate the response body: {}", e);
+                            Response::new(500)
+                        }
+                    };
+                }
+                (
+                    response,
+                    request.keep_alive(),
+                    request.version == "HTTP/1.0",
+                )
+            }
+            Ok(None) => return false,
+            Err(ParseError::Io(e)) if request::is_timeout(&e) => {
+                log!("Timed out reading request");
+                (Response::new(408), false, false)
+            }
+            Err(ParseError::Io(e)) => {
+                log!("Failed to read request: {}", e);
+                return false;
+            }
+            Err(e) => {
+                log!("Failed to parse request: {}", e);
+                (Response::new(e.status()), false, false)
+            }
+        };
+        if response
+            .header("Connection")
+            .is_some_and(|value| value.eq_ignore_ascii_case("close"))
+            || !shared.running.load(Ordering::Acquire)
+        {
+            keep_alive = false;
+        }
+        if !keep_alive {
+            response.set_header("Connection", "close");
+        } else if http_1_0 {
+            response.set_header("Connection", "keep-alive");
+            response.set_header(
+                "Keep-Alive",
+                format!("timeout={}", config.keep_alive_timeout.as_secs()),
+            );
+        }
+        if let Err(e) = response.write_to(buf_reader.get_mut()) {
+            log!("Failed to write response: {}", e);
+            return false;
+        }
+        if !keep_alive {
+            return false;
+        }
+        // The next request is waited for off the worker unless it's pipelined
+        if buf_reader.buffer().is_empty() {
+            return true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::BufRead;
+
+    /// Starts a server on a random port, returning its address and a handle to stop it.
+    fn start(router: Router, config: ServerConfig) -> (SocketAddr, ShutdownHandle) {
+        let server = Server::bind("127.0.0.1:0", router, ThreadPool::new(2))
+            .unwrap()
+            .with_config(config);
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        thread::spawn(move || server.run());
+        (addr, shutdown)
+    }
+
+    /// Reads one response with a `Content-Length` or chunked body, returning its head and body.
+    fn read_response<R: BufRead>(reader: &mut R) -> (String, String) {
+        let mut head = String::new();
+        let mut length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            if let Some(value) = line.strip_prefix("Content-Length: ") {
+                length = value.trim().parse().unwrap();
+            }
+            if line == "\r\n" {
+                break;
+            }
+            head.push_str(&line);
+        }
+        if !head.contains("Transfer-Encoding: chunked\r\n") {
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            return (head, String::from_utf8(body).unwrap());
+        }
+        let mut body = vec![];
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let size = usize::from_str_radix(line.trim_end(), 16).unwrap();
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).unwrap();
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+        (head, String::from_utf8(body).unwrap())
+    }
+
+    #[test]
+    fn shutdown_wakes_up_acceptor() {
+        let server = Server::bind("127.0.0.1:0", Router::new(), ThreadPool::new(1)).unwrap();
+        let shutdown = server.shutdown_handle();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            server.run();
+            sender.send(()).unwrap();
+        });
+        thread::sleep(Duration::from_millis(50));
+        shutdown.shutdown();
+        receiver.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    fn drain_on_shutdown() {
+        let mut router = Router::new();
+        router
+            .get("/slow", |_| {
+                thread::sleep(Duration::from_millis(200));
+                Response::new(200).with_body("slow")
+            })
+            .get("/fast", |_| Response::new(200).with_body("fast"));
+        let (addr, shutdown) = start(router, ServerConfig::default());
+
+        let mut idle = TcpStream::connect(addr).unwrap();
+        idle.write_all(b"GET /fast HTTP/1.1\r\n\r\n").unwrap();
+        let mut idle = BufReader::new(idle);
+        assert_eq!("fast", read_response(&mut idle).1);
+
+        let mut busy = TcpStream::connect(addr).unwrap();
+        busy.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let start = std::time::Instant::now();
+        shutdown.shutdown();
+
+        // The idle connection is closed right away while the in-flight request is served
+        assert_eq!(0, idle.read(&mut [0; 1]).unwrap());
+        assert!(start.elapsed() < Duration::from_millis(150));
+        let mut busy = BufReader::new(busy);
+        let (head, body) = read_response(&mut busy);
+        assert!(head.contains("Connection: close\r\n"));
+        assert_eq!("slow", body);
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn reject_when_pool_is_full() {
+        let mut router = Router::new();
+        router.get("/slow", |_| {
+            thread::sleep(Duration::from_millis(200));
+            Response::new(200).with_body("slow")
+        });
+        let pool = crate::thread_pool::Builder::new(1)
+            .queue_capacity(1)
+            .build();
+        let server = Server::bind("127.0.0.1:0", router, pool).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        thread::spawn(move || server.run());
+
+        let mut streams = vec![];
+        for i in 0..3 {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET /slow HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            if i == 2 {
+                // The unread input mustn't turn closing the rejected connection into a reset
+                stream.write_all(&[b'a'; 32 * 1024]).unwrap();
+            }
+            streams.push(BufReader::new(stream));
+            thread::sleep(Duration::from_millis(20));
+        }
+        let (head, _) = read_response(&mut streams[2]);
+        assert!(head.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(head.contains("Retry-After: 1\r\n"));
+        assert_eq!(0, streams[2].read(&mut [0; 16]).unwrap());
+        assert_eq!("slow", read_response(&mut streams[0]).1);
+        assert_eq!("slow", read_response(&mut streams[1]).1);
+
+        shutdown.shutdown();
+    }
+
+    #[test]
+    fn keep_alive_and_pipelining() {
+        let mut router = Router::new();
+        router
+            .get("/slow", |_| {
+                thread::sleep(Duration::from_millis(100));
+                Response::new(200).with_body("slow")
+            })
+            .get("/fast", |_| Response::new(200).with_body("fast"));
+        let config = ServerConfig {
+            keep_alive_timeout: Duration::from_millis(300),
+            ..Default::default()
+        };
+        let (addr, shutdown) = start(router, config);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"GET /slow HTTP/1.1\r\n\r\nGET /fast HTTP/1.1\r\n\r\nGET /nope HTTP/1.1\r\n\r\n",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        assert_eq!("slow", read_response(&mut reader).1);
+        assert_eq!("fast", read_response(&mut reader).1);
+        assert!(read_response(&mut reader).0.starts_with("HTTP/1.1 404"));
+
+        stream
+            .write_all(b"GET /fast HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let (head, body) = read_response(&mut reader);
+        assert!(head.contains("Connection: close\r\n"));
+        assert_eq!("fast", body);
+        assert_eq!(0, reader.read(&mut [0; 1]).unwrap());
+
+        // An idle connection is closed after the keep-alive timeout
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /fast HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(stream);
+        let (head, _) = read_response(&mut reader);
+        assert!(head.contains("Connection: keep-alive\r\n"));
+        assert_eq!(0, reader.read(&mut [0; 1]).unwrap());
+
+        shutdown.shutdown();
+    }
+
+    #[test]
+    fn idle_connections_free_workers() {
+        let mut router = Router::new();
+        router.get("/", |_| Response::new(200).with_body("hello"));
+        let server = Server::bind("127.0.0.1:0", router, ThreadPool::new(1))
+            .unwrap()
+            .with_config(ServerConfig {
+                keep_alive_timeout: Duration::from_millis(500),
+                ..Default::default()
+            });
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        thread::spawn(move || server.run());
+        let get = |mut stream: &TcpStream| {
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            read_response(&mut BufReader::new(stream)).1
+        };
+
+        // The only worker isn't held by the kept-alive connections between their requests
+        let start = Instant::now();
+        let streams = (0..3)
+            .map(|_| TcpStream::connect(addr).unwrap())
+            .collect::<Vec<_>>();
+        for stream in streams.iter().chain(streams.iter().rev()) {
+            assert_eq!("hello", get(stream));
+        }
+        assert!(start.elapsed() < Duration::from_millis(400));
+
+        // Until they are closed for being idle
+        for mut stream in &streams {
+            assert_eq!(0, stream.read(&mut [0; 1]).unwrap());
+        }
+        shutdown.shutdown();
+    }
+
+    #[test]
+    fn respond_500_when_handler_panics() {
+        let mut router = Router::new();
+        router
+            .get("/panic", |_| panic!("oops"))
+            .get("/fast", |_| Response::new(200).with_body("fast"));
+        let (addr, shutdown) = start(router, ServerConfig::default());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"GET /panic HTTP/1.1\r\n\r\nGET /fast HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let mut reader = BufReader::new(stream);
+        let (head, _) = read_response(&mut reader);
+        assert!(head.starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(head.contains("Connection: close\r\n"));
+        assert_eq!(0, reader.read(&mut [0; 1]).unwrap());
+
+        // The workers keep serving other connections
+        for _ in 0..4 {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(b"GET /fast HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            assert_eq!("fast", read_response(&mut BufReader::new(stream)).1);
+        }
+
+        shutdown.shutdown();
+    }
+
+    #[test]
+    fn high_priority_requests_skip_the_queue() {
+        let mut router = Router::new();
+        router
+            .get("/slow", |_| {
+                thread::sleep(Duration::from_millis(300));
+                Response::new(200).with_body("slow")
+            })
+            .get("/health", |_| Response::new(200).with_body("OK"))
+            .high_priority("/health");
+        let pool = crate::thread_pool::Builder::new(1)
+            .reserved_workers(1)
+            .build();
+        let server = Server::bind("127.0.0.1:0", router, pool).unwrap();
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        thread::spawn(move || server.run());
+
+        let mut streams = vec![];
+        for path in ["/slow", "/slow", "/health"] {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream
+                .write_all(format!("GET {} HTTP/1.1\r\nConnection: close\r\n\r\n", path).as_bytes())
+                .unwrap();
+            streams.push(BufReader::new(stream));
+            thread::sleep(Duration::from_millis(20));
+        }
+        let start = Instant::now();
+        assert_eq!("OK", read_response(&mut streams[2]).1);
+        assert!(start.elapsed() < Duration::from_millis(200));
+        assert_eq!("slow", read_response(&mut streams[0]).1);
+        assert_eq!("slow", read_response(&mut streams[1]).1);
+
+        shutdown.shutdown();
+    }
+
+    #[test]
+    fn close_connections_without_requests() {
+        let config = ServerConfig {
+            keep_alive_timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let (addr, shutdown) = start(Router::new(), config);
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let start = Instant::now();
+        assert_eq!(0, stream.read(&mut [0; 1]).unwrap());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        shutdown.shutdown();
+    }
+
+    #[test]
+    fn request_timeouts_and_limits() {
+        let mut router = Router::new();
+        router.post("/echo", |req| {
+            Response::new(200).with_body(req.body.clone())
+        });
+        let config = ServerConfig {
+            header_read_timeout: Duration::from_millis(300),
+            body_read_timeout: Duration::from_millis(200),
+            max_header_size: 1024,
+            max_headers: 4,
+            max_body_size: 16,
+            ..Default::default()
+        };
+        let (addr, shutdown) = start(router, config);
+        let status_of = |request: &[u8]| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request).unwrap();
+            read_response(&mut BufReader::new(stream)).0
+        };
+
+        // Headers sent slowly byte by byte fail as a whole even though no read stalls
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let start = Instant::now();
+        for byte in b"POST /echo HTTP/1.1\r\nHost: localhost\r\n".iter().cycle() {
+            if stream.write_all(&[*byte]).is_err() || start.elapsed() > Duration::from_secs(2) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        let (head, _) = read_response(&mut BufReader::new(stream));
+        assert!(
+            head.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            head
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // A stalled body
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc")
+            .unwrap();
+        let (head, _) = read_response(&mut BufReader::new(stream));
+        assert!(
+            head.starts_with("HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            head
+        );
+
+        let long_path = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(1024));
+        assert!(status_of(long_path.as_bytes()).starts_with("HTTP/1.1 414 URI Too Long\r\n"));
+        let large_header = format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", "a".repeat(1024));
+        let head = status_of(large_header.as_bytes());
+        assert!(head.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        let head = status_of(b"GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\nE: 5\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+        let head = status_of(b"POST /echo HTTP/1.1\r\nContent-Length: 99999999999999\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+        let head = status_of(b"POST /echo HTTP/1.1\r\nContent-Length: 17\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+
+        // Within the limits
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(b"POST /echo HTTP/1.1\r\nContent-Length: 3\r\nA: 1\r\nB: 2\r\n\r\nabc")
+            .unwrap();
+        let (head, body) = read_response(&mut BufReader::new(stream));
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!("abc", body);
+
+        shutdown.shutdown();
+    }
+
+    #[test]
+    fn report_metrics() {
+        let metrics = Metrics::new();
+        let mut router = Router::new();
+        router
+            .wrap(metrics.clone())
+            .get("/metrics", metrics.handler());
+        let server = Server::bind("127.0.0.1:0", router, ThreadPool::new(2))
+            .unwrap()
+            .with_metrics(&metrics);
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        thread::spawn(move || server.run());
+
+        let idle = TcpStream::connect(addr).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        // The first request is observed after its response is rendered
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\n\r\nGET /metrics HTTP/1.1\r\n\r\n")
+            .unwrap();
+        read_response(&mut reader);
+        let (_, body) = read_response(&mut reader);
+        for line in [
+            // The idle connection is counted as soon as it's accepted
+            "http_connections_active 2",
+            // The worker serving this connection
+            "thread_pool_busy_workers 1",
+            "http_requests_total{method=\"GET\",route=\"/metrics\",status=\"200\"} 1",
+        ] {
+            assert!(
+                body.lines().any(|l| l == line),
+                "{} is missing in\n{}",
+                line,
+                body
+            );
+        }
+        drop(idle);
+        shutdown.shutdown();
+    }
+
+    #[test]
+    fn reload() {
+        let version = |body: &'static str| {
+            let mut router = Router::new();
+            router.get("/", move |_| Response::new(200).with_body(body));
+            router
+        };
+        let metrics = Metrics::new();
+        let pool = crate::thread_pool::Builder::new(2)
+            .scheduling(crate::thread_pool::Scheduling::Shared)
+            .max_workers(8)
+            .build();
+        let monitor = pool.monitor();
+        let server = Server::bind("127.0.0.1:0", version("v1"), pool)
+            .unwrap()
+            .with_metrics(&metrics);
+        let addr = server.local_addr().unwrap();
+        let shutdown = server.shutdown_handle();
+        let reload = server.reload_handle();
+        thread::spawn(move || server.run());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut get = || {
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+            read_response(&mut reader)
+        };
+        assert_eq!("v1", get().1);
+
+        reload.reload(
+            Reload::new()
+                .with_router(version("v2"))
+                .with_config(ServerConfig {
+                    keep_alive_timeout: Duration::from_secs(7),
+                    ..Default::default()
+                })
+                .with_workers(3),
+        );
+        // The reload is applied on the acceptor thread, the connection picks it up afterwards
+        let start = Instant::now();
+        let (head, body) = loop {
+            let (head, body) = get();
+            if body == "v2" || start.elapsed() > Duration::from_secs(2) {
+                break (head, body);
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!("v2", body);
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        stream
+            .write_all(b"GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .unwrap();
+        let (head, _) = read_response(&mut reader);
+        assert!(head.contains("Keep-Alive: timeout=7\r\n"), "{}", head);
+        assert!(metrics.render().contains("\nthread_pool_workers 3\n"));
+        // The pool can still grow as configured
+        let stats = monitor.stats();
+        assert_eq!((3, 8), (stats.min_workers, stats.max_workers));
+
+        shutdown.shutdown();
+    }
+
+    #[test]
+    fn parse_request_path() {
+        assert_eq!(Some("/health"), request_path(b"GET /health HTTP/1.1\r\n"));
+        assert_eq!(Some("/a/b"), request_path(b"GET /a/b?x=1 HTTP/1.1\r\nHost"));
+        assert_eq!(None, request_path(b"GET /incompl"));
+    }
+
+    #[test]
+    fn chunked_transfer_encoding() {
+        let mut router = Router::new();
+        router
+            .get("/stream", |_| {
+                Response::new(200).with_stream(|writer| {
+                    for i in 0..3 {
+                        writeln!(writer, "line {}", i)?;
+                        writer.flush()?;
+                    }
+                    Ok(())
+                })
+            })
+            .post("/echo", |req| {
+                Response::new(200).with_body(req.body.clone())
+            });
+        let (addr, shutdown) = start(router, ServerConfig::default());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"GET /stream HTTP/1.1\r\n\r\n\
+                  POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n\
+                  HEAD /stream HTTP/1.1\r\n\r\n\
+                  GET /stream HTTP/1.0\r\n\r\n",
+            )
+            .unwrap();
+        let mut reader = BufReader::new(stream);
+        let (head, body) = read_response(&mut reader);
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+        assert_eq!("line 0\nline 1\nline 2\n", body);
+        assert_eq!("abc", read_response(&mut reader).1);
+
+        // The response to HEAD has no chunks at all
+        let mut head = String::new();
+        while !head.ends_with("\r\n\r\n") {
+            reader.read_line(&mut head).unwrap();
+        }
+        assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+
+        // HTTP/1.0 has no chunked encoding
+        let (head, body) = read_response(&mut reader);
+        assert!(head.contains("Content-Length: 21\r\n"));
+        assert_eq!("line 0\nline 1\nline 2\n", body);
+
+        shutdown.shutdown();
+    }
+
+    #[test]
+    fn https() {
+        use rustls::{ClientConfig, ClientConnection, RootCertStore, pki_types::ServerName};
+
+        let dir = crate::static_files::TempDir::new("https");
+        let dir = &dir.0;
+        let (cert, key, der) = crate::tls::self_signed(dir, "localhost", &["localhost"]);
+        let (other_cert, other_key, other_der) =
+            crate::tls::self_signed(dir, "example", &["www.example.com"]);
+        let tls = TlsConfig::from_pem_files(&cert, &key)
+            .unwrap()
+            .with_sni_pem_files("www.example.com", &other_cert, &other_key)
+            .unwrap();
+
+        let mut router = Router::new();
+        router.get("/hello", |req| {
+            Response::new(200).with_body(format!("hello {}", req.query.as_deref().unwrap_or("")))
+        });
+        let server = Server::bind("127.0.0.1:0", router, ThreadPool::new(2))
+            .unwrap()
+            .with_tls(&tls)
+            .with_https_redirect("127.0.0.1:0")
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let redirect_addr = server.redirect_addr().unwrap().unwrap();
+        let shutdown = server.shutdown_handle();
+        thread::spawn(move || server.run());
+
+        let mut roots = RootCertStore::empty();
+        roots.add(der.clone().into()).unwrap();
+        roots.add(other_der.clone().into()).unwrap();
+        let config = Arc::new(
+            ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        );
+        let connect = |host_name: &'static str| {
+            let connection =
+                ClientConnection::new(config.clone(), ServerName::try_from(host_name).unwrap())
+                    .unwrap();
+            BufReader::new(StreamOwned::new(
+                connection,
+                TcpStream::connect(addr).unwrap(),
+            ))
+        };
+
+        for (host_name, der) in [("localhost", &der), ("www.example.com", &other_der)] {
+            let mut stream = connect(host_name);
+            // Two requests to check that the connection is kept alive
+            for i in 0..2 {
+                write!(stream.get_mut(), "GET /hello?{} HTTP/1.1\r\n\r\n", i).unwrap();
+                let (head, body) = read_response(&mut stream);
+                assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+                assert_eq!(format!("hello {}", i), body);
+            }
+            let certs = stream.get_ref().conn.peer_certificates().unwrap();
+            assert_eq!(der.as_slice(), certs[0].as_ref());
+        }
+
+        // Plain HTTP isn't served on the HTTPS listener
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /hello HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = vec![];
+        let _ = stream.read_to_end(&mut response);
+        assert!(!response.starts_with(b"HTTP/1.1 200"));
+
+        let mut stream = TcpStream::connect(redirect_addr).unwrap();
+        write!(
+            stream,
+            "GET /hello?x=1 HTTP/1.1\r\nHost: localhost:{}\r\n\r\n",
+            redirect_addr.port()
+        )
+        .unwrap();
+        let (head, _) = read_response(&mut BufReader::new(stream));
+        assert!(head.starts_with("HTTP/1.1 308 Permanent Redirect\r\n"));
+        assert!(head.contains(&format!(
+            "Location: https://localhost:{}/hello?x=1\r\n",
+            addr.port()
+        )));
+
+        shutdown.shutdown();
+    }
+}
@@ -0,0 +1,675 @@
+// Copyright 2025 Yunze Xu
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::{
+    compression::{self, Encoding},
+    log,
+    request::Request,
+    response::Response,
+};
+use chrono::{DateTime, Utc};
+use std::{
+    fs::{self, File, Metadata},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Serves files under a document root.
+///
+/// The file is chosen by the `path` parameter when the route has one (e.g. `/static/*path`),
+/// otherwise by the whole request path. A directory is served by its `index.html`. Paths that
+/// would leave the root, either by `..` segments or by following symbolic links, are answered
+/// with 404 just like missing files. Files are streamed rather than read into memory.
+///
+/// Responses carry an `ETag` and a `Last-Modified`, so that clients can revalidate their cached
+/// copies with `If-None-Match` or `If-Modified-Since` and get `304 Not Modified` back. Byte
+/// ranges are served with `206 Partial Content`, so that downloads can be resumed.
+pub struct StaticFiles {
+    root: PathBuf,
+    cache_control: Option<String>,
+    precompressed: bool,
+}
+
+/// The encodings of precompressed files and their extensions, in order of preference.
+const PRECOMPRESSED: [(Encoding, &str); 2] = [(Encoding::Brotli, "br"), (Encoding::Gzip, "gz")];
+
+impl StaticFiles {
+    /// Creates a server for files under `root`, which must be an existing directory.
+    pub fn new<P: AsRef<Path>>(root: P) -> io::Result<Self> {
+        let root = fs::canonicalize(root)?;
+        if !root.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not a directory", root.display()),
+            ));
+        }
+        Ok(StaticFiles {
+            root,
+            cache_control: Some("no-cache".to_string()),
+            precompressed: false,
+        })
+    }
+
+    /// Sets the `Cache-Control` of the responses, which is `no-cache` by default so that clients
+    /// revalidate their cached copies before using them. `None` omits the header.
+    pub fn with_cache_control(mut self, cache_control: Option<&str>) -> Self {
+        self.cache_control = cache_control.map(str::to_string);
+        self
+    }
+
+    /// Serves `file.br` or `file.gz` instead of `file` with a `Content-Encoding` if it exists and
+    /// the client accepts it, so that files compressed ahead of time (e.g. by `brotli -k` or
+    /// `gzip -k`) don't need to be compressed on every request.
+    pub fn with_precompressed(mut self, precompressed: bool) -> Self {
+        self.precompressed = precompressed;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn serve(&self, request: &Request) -> Response {
+        let path = request.param("path").unwrap_or(&request.path);
+        let Some(path) = percent_decode(path) else {
+            return Response::new(400);
+        };
+        let Some(file) = self.resolve(&path) else {
+            return Response::new(404);
+        };
+        if file.is_dir() {
+            if !request.path.ends_with('/') {
+                let location = match &request.query {
+                    Some(query) => format!("{}/?{}", request.path, query),
+                    None => format!("{}/", request.path),
+                };
+                return Response::new(301).with_header("Location", location);
+            }
+            return match self.resolve(&format!("{}/index.html", path)) {
+                Some(index) if index.is_file() => self.serve_file(request, &index),
+                _ => Response::new(404),
+            };
+        }
+        self.serve_file(request, &file)
+    }
+
+    fn serve_file(&self, request: &Request, file: &Path) -> Response {
+        let content_type = content_type(file);
+        if !self.precompressed {
+            return self.read(request, file, content_type);
+        }
+        let siblings = PRECOMPRESSED
+            .iter()
+            .filter_map(|&(encoding, extension)| {
+                let mut sibling = file.as_os_str().to_owned();
+                sibling.push(".");
+                sibling.push(extension);
+                // The sibling might be a symbolic link as well
+                let sibling = fs::canonicalize(sibling).ok()?;
+                (sibling.starts_with(&self.root) && sibling.is_file())
+                    .then_some((encoding, sibling))
+            })
+            .collect::<Vec<_>>();
+        if siblings.is_empty() {
+            return self.read(request, file, content_type);
+        }
+        let encodings = siblings
+            .iter()
+            .map(|(encoding, _)| *encoding)
+            .collect::<Vec<_>>();
+        let mut response = match compression::negotiate(request, &encodings) {
+            Some(encoding) => {
+                let (_, sibling) = siblings.iter().find(|(e, _)| *e == encoding).unwrap();
+                let mut response = self.read(request, sibling, content_type);
+                if matches!(response.status, 200 | 206) {
+                    response.set_header("Content-Encoding", encoding.name());
+                }
+                response
+            }
+            None => self.read(request, file, content_type),
+        };
+        compression::add_vary(&mut response, "Accept-Encoding");
+        response
+    }
+
+    /// Maps a decoded request path to a canonical path under the root.
+    fn resolve(&self, path: &str) -> Option<PathBuf> {
+        let mut file = self.root.clone();
+        for segment in path.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return None,
+                _ if segment.contains(['\\', '\0']) => return None,
+                _ => file.push(segment),
+            }
+        }
+        // Resolve symbolic links so that a link pointing outside the root is rejected
+        let file = fs::canonicalize(file).ok()?;
+        file.starts_with(&self.root).then_some(file)
+    }
+
+    fn read(&self, request: &Request, file: &Path, content_type: &str) -> Response {
+        let (metadata, f) = match File::open(file).and_then(|f| Ok((f.metadata()?, f))) {
+            Ok(opened) => opened,
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => return Response::new(403),
+            Err(e) => {
+                log!("Failed to read {}: {}", file.display(), e);
+                return Response::new(500);
+            }
+        };
+        let etag = etag(&metadata);
+        let modified = metadata.modified().ok();
+        let length = metadata.len();
+        let mut response = if is_fresh(request, &etag, modified) {
+            Response::new(304)
+        } else {
+            match requested_ranges(request, &etag, modified, length) {
+                None => stream_file(f, vec![(0, length)], 200)
+                    .with_header("Content-Length", length.to_string())
+                    .with_header("Content-Type", content_type),
+                Some(ranges) if ranges.is_empty() => {
+                    Response::new(416).with_header("Content-Range", format!("bytes */{}", length))
+                }
+                Some(ranges) if ranges.len() == 1 => {
+                    let (start, end) = ranges[0];
+                    stream_file(f, ranges, 206)
+                        .with_header("Content-Length", (end - start).to_string())
+                        .with_header("Content-Type", content_type)
+                        .with_header(
+                            "Content-Range",
+                            format!("bytes {}-{}/{}", start, end - 1, length),
+                        )
+                }
+                Some(ranges) => multipart(f, ranges, length, content_type),
+            }
+        };
+        response.set_header("Accept-Ranges", "bytes");
+        response.set_header("ETag", etag);
+        if let Some(modified) = modified {
+            response.set_header("Last-Modified", http_date(modified));
+        }
+        if let Some(cache_control) = &self.cache_control {
+            response.set_header("Cache-Control", cache_control.as_str());
+        }
+        response
+    }
+}
+
+/// The most ranges served in one response, beyond which the `Range` header is ignored so that a
+/// request can't make the server seek all over a file.
+const MAX_RANGES: usize = 16;
+
+/// Returns the byte ranges to serve as `[start, end)` pairs, empty if none is satisfiable, or
+/// `None` if the whole file should be served. See RFC 9110 14.
+fn requested_ranges(
+    request: &Request,
+    etag: &str,
+    modified: Option<SystemTime>,
+    length: u64,
+) -> Option<Vec<(u64, u64)>> {
+    if request.method != "GET" && request.method != "HEAD" {
+        return None;
+    }
+    let value = request.header("Range")?;
+    // Ranges of an outdated copy would be mixed up, so the whole file is sent instead
+    if let Some(validator) = request.header("If-Range") {
+        let matched = if validator.starts_with('"') {
+            validator == etag
+        } else {
+            modified.is_some_and(|modified| http_date(modified) == validator)
+        };
+        if !matched {
+            return None;
+        }
+    }
+    let specs = value.trim().strip_prefix("bytes=")?.split(',');
+    let mut ranges = vec![];
+    for spec in specs {
+        let (first, last) = spec.trim().split_once('-')?;
+        let range = match (first, last) {
+            ("", suffix) => {
+                let suffix = suffix.parse::<u64>().ok()?;
+                (suffix > 0 && length > 0).then(|| (length.saturating_sub(suffix), length))
+            }
+            (first, "") => {
+                let first = first.parse::<u64>().ok()?;
+                (first < length).then_some((first, length))
+            }
+            (first, last) => {
+                let (first, last) = (first.parse::<u64>().ok()?, last.parse::<u64>().ok()?);
+                if first > last {
+                    return None;
+                }
+                (first < length).then(|| (first, last.min(length - 1) + 1))
+            }
+        };
+        ranges.extend(range);
+    }
+    (ranges.len() <= MAX_RANGES).then_some(ranges)
+}
+
+/// Streams the `[start, end)` ranges of the file.
+fn stream_file(f: File, ranges: Vec<(u64, u64)>, status: u16) -> Response {
+    Response::new(status).with_stream(move |writer| {
+        let mut f = f;
+        for (start, end) in ranges {
+            copy_range(&mut f, start, end, writer)?;
+        }
+        Ok(())
+    })
+}
+
+fn copy_range(f: &mut File, start: u64, end: u64, writer: &mut dyn Write) -> io::Result<()> {
+    f.seek(SeekFrom::Start(start))?;
+    // The file might be truncated meanwhile, which must not break the framing
+    let copied = io::copy(&mut f.take(end - start), writer)?;
+    if copied < end - start {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the file was truncated while being sent",
+        ));
+    }
+    Ok(())
+}
+
+/// Serves several ranges as `multipart/byteranges`, see RFC 9110 14.6.
+fn multipart(f: File, ranges: Vec<(u64, u64)>, length: u64, content_type: &str) -> Response {
+    let boundary = format!(
+        "{:x}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    );
+    let parts = ranges
+        .into_iter()
+        .map(|(start, end)| {
+            let head = format!(
+                "--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                boundary,
+                content_type,
+                start,
+                end - 1,
+                length
+            );
+            (head, start, end)
+        })
+        .collect::<Vec<_>>();
+    let tail = format!("--{}--\r\n", boundary);
+    let content_length = parts
+        .iter()
+        .map(|(head, start, end)| head.len() as u64 + end - start + 2)
+        .sum::<u64>()
+        + tail.len() as u64;
+    Response::new(206)
+        .with_header("Content-Length", content_length.to_string())
+        .with_header(
+            "Content-Type",
+            format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .with_stream(move |writer| {
+            let mut f = f;
+            for (head, start, end) in parts {
+                writer.write_all(head.as_bytes())?;
+                copy_range(&mut f, start, end, writer)?;
+                writer.write_all(b"\r\n")?;
+            }
+            writer.write_all(tail.as_bytes())
+        })
+}
+
+/// Returns a strong validator that changes whenever the file is modified, like nginx does.
+fn etag(metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .unwrap_or_default();
+    format!("\"{:x}-{:x}\"", modified.as_nanos(), metadata.len())
+}
+
+/// Whether the client's cached copy is still valid, see RFC 9110 13.2.2.
+fn is_fresh(request: &Request, etag: &str, modified: Option<SystemTime>) -> bool {
+    if request.method != "GET" && request.method != "HEAD" {
+        return false;
+    }
+    // `If-Modified-Since` is ignored when `If-None-Match` is present
+    if let Some(value) = request.header("If-None-Match") {
+        let opaque = |tag: &str| tag.trim_start_matches("W/").to_string();
+        return value
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || opaque(tag) == opaque(etag));
+    }
+    let (Some(since), Some(modified)) = (
+        request
+            .header("If-Modified-Since")
+            .and_then(parse_http_date),
+        modified,
+    ) else {
+        return false;
+    };
+    // The date only has a precision of seconds
+    modified
+        .duration_since(UNIX_EPOCH)
+        .is_ok_and(|modified| modified.as_secs() <= since.timestamp() as u64)
+}
+
+/// Formats `time` as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+
+fn parse_http_date(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(value)
+        .ok()
+        .map(|date| date.with_timezone(&Utc))
+}
+
+/// Guesses the MIME type from the file extension.
+pub fn content_type(file: &Path) -> &'static str {
+    let extension = file
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript; charset=utf-8",
+        Some("json") => "application/json",
+        Some("txt" | "log") => "text/plain; charset=utf-8",
+        Some("csv") => "text/csv; charset=utf-8",
+        Some("md") => "text/markdown; charset=utf-8",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("ttf") => "font/ttf",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        Some("zip") => "application/zip",
+        Some("gz") => "application/gzip",
+        Some("tar") => "application/x-tar",
+        Some("mp3") => "audio/mpeg",
+        Some("mp4") => "video/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Decodes `%XX` escapes, returning `None` for invalid escapes or non UTF-8 results.
+fn percent_decode(input: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(input.len());
+    let mut iter = input.bytes();
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// An empty directory for the files of a test, removed with everything in it when dropped.
+#[cfg(test)]
+pub(crate) struct TempDir(pub(crate) PathBuf);
+
+#[cfg(test)]
+impl TempDir {
+    /// Creates `web-server-{name}-{pid}` under the temporary directory, so `name` must be unique
+    /// among the tests.
+    pub(crate) fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("web-server-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(path: &str) -> Request {
+        let raw = format!("GET {} HTTP/1.1\r\n\r\n", path);
+        Request::parse(&mut raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn body(response: Response) -> Vec<u8> {
+        response.body.into_bytes().unwrap()
+    }
+
+    #[test]
+    fn serve_files() {
+        let dir = TempDir::new("static-files");
+        let root = dir.0.join("root");
+        fs::create_dir_all(root.join("docs")).unwrap();
+        fs::write(root.join("index.html"), "<h1>index</h1>").unwrap();
+        fs::write(root.join("docs/a b.png"), [0x89, b'P', b'N', b'G', 0xff]).unwrap();
+        fs::write(dir.0.join("secret.txt"), "secret").unwrap();
+        let files = StaticFiles::new(&root).unwrap();
+
+        let response = files.serve(&request("/"));
+        assert_eq!(200, response.status);
+        assert_eq!(Some("14"), response.header("Content-Length"));
+        assert_eq!(
+            Some("text/html; charset=utf-8"),
+            response.header("Content-Type")
+        );
+        assert_eq!(b"<h1>index</h1>", body(response).as_slice());
+
+        let response = files.serve(&request("/docs/a%20b.png"));
+        assert_eq!(200, response.status);
+        assert_eq!(Some("image/png"), response.header("Content-Type"));
+        assert_eq!(&[0x89, b'P', b'N', b'G', 0xff], body(response).as_slice());
+
+        let response = files.serve(&request("/docs?x=1"));
+        assert_eq!(301, response.status);
+        assert_eq!(Some("/docs/?x=1"), response.header("Location"));
+        assert_eq!(404, files.serve(&request("/docs/")).status);
+
+        for path in ["/../secret.txt", "/docs/%2e%2e/../secret.txt", "/missing"] {
+            assert_eq!(404, files.serve(&request(path)).status, "{}", path);
+        }
+        assert_eq!(400, files.serve(&request("/%zz")).status);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reject_symlink_escape() {
+        let dir = TempDir::new("static-files-symlink");
+        let root = dir.0.join("root");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(dir.0.join("secret.txt"), "secret").unwrap();
+        fs::write(root.join("inside.txt"), "inside").unwrap();
+        std::os::unix::fs::symlink(dir.0.join("secret.txt"), root.join("escape.txt")).unwrap();
+        std::os::unix::fs::symlink(&dir.0, root.join("parent")).unwrap();
+        std::os::unix::fs::symlink(root.join("inside.txt"), root.join("link.txt")).unwrap();
+        let files = StaticFiles::new(&root).unwrap();
+
+        assert_eq!(404, files.serve(&request("/escape.txt")).status);
+        assert_eq!(404, files.serve(&request("/parent/secret.txt")).status);
+        assert_eq!(200, files.serve(&request("/link.txt")).status);
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let dir = TempDir::new("conditional");
+        fs::write(dir.0.join("app.js"), "let x = 1;").unwrap();
+        let files = StaticFiles::new(&dir.0)
+            .unwrap()
+            .with_cache_control(Some("public, max-age=60"));
+        let conditional = |name: &str, value: &str| {
+            let raw = format!("GET /app.js HTTP/1.1\r\n{}: {}\r\n\r\n", name, value);
+            files.serve(&Request::parse(&mut raw.as_bytes()).unwrap().unwrap())
+        };
+
+        let response = files.serve(&request("/app.js"));
+        assert_eq!(200, response.status);
+        assert_eq!(Some("public, max-age=60"), response.header("Cache-Control"));
+        let etag = response.header("ETag").unwrap().to_string();
+        let last_modified = response.header("Last-Modified").unwrap().to_string();
+        assert!(last_modified.ends_with(" GMT"));
+
+        let response = conditional("If-None-Match", &format!("\"x\", W/{}", etag));
+        assert_eq!(304, response.status);
+        assert_eq!(Some(etag.as_str()), response.header("ETag"));
+        assert!(response.body.as_bytes().unwrap().is_empty());
+        assert_eq!(304, conditional("If-None-Match", "*").status);
+        assert_eq!(200, conditional("If-None-Match", "\"x\"").status);
+
+        assert_eq!(304, conditional("If-Modified-Since", &last_modified).status);
+        assert_eq!(
+            200,
+            conditional("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT").status
+        );
+        assert_eq!(200, conditional("If-Modified-Since", "yesterday").status);
+
+        let files = StaticFiles::new(&dir.0).unwrap().with_cache_control(None);
+        assert_eq!(
+            None,
+            files.serve(&request("/app.js")).header("Cache-Control")
+        );
+    }
+
+    #[test]
+    fn range_requests() {
+        let dir = TempDir::new("ranges");
+        fs::write(dir.0.join("data.txt"), "0123456789").unwrap();
+        let files = StaticFiles::new(&dir.0).unwrap();
+        let get = |headers: &str| {
+            let raw = format!("GET /data.txt HTTP/1.1\r\n{}\r\n", headers);
+            files.serve(&Request::parse(&mut raw.as_bytes()).unwrap().unwrap())
+        };
+        let range = |value: &str| get(&format!("Range: {}\r\n", value));
+
+        let response = get("");
+        assert_eq!(200, response.status);
+        assert_eq!(Some("bytes"), response.header("Accept-Ranges"));
+        let etag = response.header("ETag").unwrap().to_string();
+
+        for (value, content_range, expected) in [
+            ("bytes=2-4", "bytes 2-4/10", "234"),
+            ("bytes=7-", "bytes 7-9/10", "789"),
+            ("bytes=-3", "bytes 7-9/10", "789"),
+            ("bytes=8-100", "bytes 8-9/10", "89"),
+            ("bytes=-100", "bytes 0-9/10", "0123456789"),
+        ] {
+            let response = range(value);
+            assert_eq!(206, response.status, "{}", value);
+            assert_eq!(Some(content_range), response.header("Content-Range"));
+            assert_eq!(
+                Some(expected.len().to_string().as_str()),
+                response.header("Content-Length")
+            );
+            assert_eq!(expected.as_bytes(), body(response).as_slice());
+        }
+
+        let response = range("bytes=10-, -0");
+        assert_eq!(416, response.status);
+        assert_eq!(Some("bytes */10"), response.header("Content-Range"));
+
+        // Malformed ranges are ignored
+        for value in ["items=1-2", "bytes=4-2", "bytes=a-b", "bytes=1"] {
+            assert_eq!(200, range(value).status, "{}", value);
+        }
+
+        let response = get(&format!("Range: bytes=0-1\r\nIf-Range: {}\r\n", etag));
+        assert_eq!(206, response.status);
+        let response = get("Range: bytes=0-1\r\nIf-Range: \"stale\"\r\n");
+        assert_eq!(200, response.status);
+
+        let response = range("bytes=0-1, 5-5");
+        assert_eq!(206, response.status);
+        let content_type = response.header("Content-Type").unwrap().to_string();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap()
+            .to_string();
+        let length = response.header("Content-Length").unwrap().parse::<usize>();
+        let body = String::from_utf8(body(response)).unwrap();
+        assert_eq!(Ok(body.len()), length);
+        assert_eq!(
+            format!(
+                "--{b}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+                 --{b}\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Range: bytes 5-5/10\r\n\r\n5\r\n\
+                 --{b}--\r\n",
+                b = boundary
+            ),
+            body
+        );
+    }
+
+    #[test]
+    fn precompressed_files() {
+        let dir = TempDir::new("precompressed");
+        fs::write(dir.0.join("app.js"), "plain").unwrap();
+        fs::write(dir.0.join("app.js.gz"), "gzipped").unwrap();
+        fs::write(dir.0.join("app.js.br"), "brotli").unwrap();
+        fs::write(dir.0.join("style.css"), "plain").unwrap();
+        let files = StaticFiles::new(&dir.0).unwrap().with_precompressed(true);
+        let serve = |path: &str, accept_encoding: &str| {
+            let raw = format!(
+                "GET {} HTTP/1.1\r\nAccept-Encoding: {}\r\n\r\n",
+                path, accept_encoding
+            );
+            files.serve(&Request::parse(&mut raw.as_bytes()).unwrap().unwrap())
+        };
+
+        let response = serve("/app.js", "gzip, br");
+        assert_eq!(Some("br"), response.header("Content-Encoding"));
+        assert_eq!(
+            Some("text/javascript; charset=utf-8"),
+            response.header("Content-Type")
+        );
+        assert_eq!(Some("Accept-Encoding"), response.header("Vary"));
+        assert_eq!(b"brotli", body(response).as_slice());
+
+        let response = serve("/app.js", "gzip");
+        assert_eq!(Some("gzip"), response.header("Content-Encoding"));
+        assert_eq!(b"gzipped", body(response).as_slice());
+
+        let response = serve("/app.js", "identity");
+        assert_eq!(None, response.header("Content-Encoding"));
+        assert_eq!(Some("Accept-Encoding"), response.header("Vary"));
+        assert_eq!(b"plain", body(response).as_slice());
+
+        // Without siblings the response doesn't depend on `Accept-Encoding`
+        let response = serve("/style.css", "gzip");
+        assert_eq!(None, response.header("Content-Encoding"));
+        assert_eq!(None, response.header("Vary"));
+
+        let files = StaticFiles::new(&dir.0).unwrap();
+        let raw = "GET /app.js HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n";
+        let response = files.serve(&Request::parse(&mut raw.as_bytes()).unwrap().unwrap());
+        assert_eq!(b"plain", body(response).as_slice());
+    }
+}
@@ -14,79 +14,1169 @@
 
 use crate::log;
 use std::{
+    any::Any,
+    collections::{BTreeMap, HashMap},
+    fmt,
+    marker::PhantomData,
+    mem,
+    panic::{self, AssertUnwindSafe},
     sync::{
-        Arc,
-        atomic::AtomicBool,
-        mpsc::{Sender, channel},
+        Arc, Condvar, Mutex,
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc::{Receiver, RecvTimeoutError, SendError, Sender, SyncSender, channel, sync_channel},
     },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
+/// How [`ThreadPool::execute`] assigns jobs to workers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Scheduling {
+    /// Each worker has its own queue and jobs are assigned in a round-robin way, so a slow job
+    /// delays the jobs queued behind it even if other workers are idle.
+    #[default]
+    RoundRobin,
+    /// All workers take jobs from a shared queue, so a job only waits when all workers are busy.
+    Shared,
+}
+
+/// The lane of a job, see [`ThreadPool::execute_with_priority`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Priority {
+    /// Jumps ahead of the queued normal jobs and runs on the reserved workers if there are any.
+    High,
+    #[default]
+    Normal,
+}
+
+/// Configures a [`ThreadPool`], e.g.
+///
+/// ```
+/// use std::time::Duration;
+/// use web_server::thread_pool::{Builder, Scheduling};
+///
+/// let pool = Builder::new(4)
+///     .scheduling(Scheduling::Shared)
+///     .queue_capacity(64)
+///     .max_workers(16)
+///     .keep_alive(Duration::from_secs(30))
+///     .reserved_workers(1)
+///     .build();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Builder {
+    num_workers: usize,
+    scheduling: Scheduling,
+    queue_capacity: Option<usize>,
+    min_workers: Option<usize>,
+    max_workers: Option<usize>,
+    keep_alive: Duration,
+    reserved_workers: usize,
+}
+
+impl Builder {
+    pub fn new(num_workers: usize) -> Self {
+        Builder {
+            num_workers,
+            scheduling: Scheduling::default(),
+            queue_capacity: None,
+            min_workers: None,
+            max_workers: None,
+            keep_alive: Duration::from_secs(60),
+            reserved_workers: 0,
+        }
+    }
+
+    pub fn scheduling(mut self, scheduling: Scheduling) -> Self {
+        self.scheduling = scheduling;
+        self
+    }
+
+    /// Bounds how many jobs can wait in each queue, i.e. per worker with
+    /// [`Scheduling::RoundRobin`] and for the whole pool with [`Scheduling::Shared`]. The queues
+    /// are unbounded by default.
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = Some(capacity);
+        self
+    }
+
+    /// The number of workers that idle workers retire down to, which is the initial number of
+    /// workers by default. Only allowed with [`Scheduling::Shared`].
+    pub fn min_workers(mut self, min_workers: usize) -> Self {
+        self.min_workers = Some(min_workers);
+        self
+    }
+
+    /// The number of workers that the pool grows up to when jobs keep waiting because all
+    /// workers are busy, which is the initial number of workers by default. Only allowed with
+    /// [`Scheduling::Shared`].
+    pub fn max_workers(mut self, max_workers: usize) -> Self {
+        self.max_workers = Some(max_workers);
+        self
+    }
+
+    /// How long a worker waits for a job before it retires, if there are more than
+    /// [`Builder::min_workers`] workers. It's 60 seconds by default.
+    pub fn keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive;
+        self
+    }
+
+    /// The number of extra workers that only run [`Priority::High`] jobs, so that they don't wait
+    /// even if all other workers are busy. There are none by default.
+    pub fn reserved_workers(mut self, reserved_workers: usize) -> Self {
+        self.reserved_workers = reserved_workers;
+        self
+    }
+
+    /// # Panics
+    ///
+    /// Panics if the maximum number of workers is 0 or less than the minimum, or if either is
+    /// set with [`Scheduling::RoundRobin`].
+    pub fn build(self) -> ThreadPool {
+        let (min_workers, max_workers) = match self.scheduling {
+            Scheduling::RoundRobin => {
+                assert!(
+                    self.min_workers.is_none() && self.max_workers.is_none(),
+                    "min_workers and max_workers require Scheduling::Shared"
+                );
+                (self.num_workers, self.num_workers)
+            }
+            Scheduling::Shared => (
+                self.min_workers.unwrap_or(self.num_workers),
+                self.max_workers.unwrap_or(self.num_workers),
+            ),
+        };
+        assert!(max_workers > 0, "a thread pool needs at least one worker");
+        assert!(
+            min_workers <= max_workers,
+            "min_workers ({}) is greater than max_workers ({})",
+            min_workers,
+            max_workers
+        );
+        let (exited_sender, exited) = channel();
+        let high_priority = Queue::new(self.queue_capacity);
+        let mut pool = ThreadPool {
+            workers: HashMap::new(),
+            next_id: 0,
+            queues: vec![],
+            state: Arc::new(State {
+                abandoned: AtomicBool::new(false),
+                workers: AtomicUsize::new(0),
+                idle_workers: AtomicUsize::new(0),
+                reserved_workers: self.reserved_workers,
+                min_workers: AtomicUsize::new(min_workers),
+                max_workers: AtomicUsize::new(max_workers),
+                keep_alive: self.keep_alive,
+                completed_jobs: AtomicU64::new(0),
+                high_priority_jobs: high_priority.jobs.pending.clone(),
+                statuses: Mutex::new(BTreeMap::new()),
+            }),
+            high_priority: Some(high_priority),
+            scheduling: self.scheduling,
+            queue_capacity: self.queue_capacity,
+            active_id: 0,
+            busy_since: None,
+            exited,
+            exited_sender,
+        };
+        for _ in 0..self.num_workers.clamp(min_workers, max_workers) {
+            pool.spawn_worker();
+        }
+        for _ in 0..self.reserved_workers {
+            let jobs = pool.high_priority.as_ref().unwrap().jobs.clone();
+            pool.add_worker(Kind::Reserved, jobs, None);
+        }
+        pool
+    }
+}
+
+/// The error of [`ThreadPool::try_execute`] when all queues are full, which gives the job back.
+pub struct Full<F>(pub F);
+
+impl<F> fmt::Debug for Full<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Full(..)")
+    }
+}
+
+impl<F> fmt::Display for Full<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the queues of the thread pool are full")
+    }
+}
+
+impl<F> std::error::Error for Full<F> {}
+
+/// A snapshot of a [`ThreadPool`], see [`ThreadPool::stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of workers, either idle or busy, except the reserved ones.
+    pub workers: usize,
+    pub idle_workers: usize,
+    pub reserved_workers: usize,
+    pub min_workers: usize,
+    pub max_workers: usize,
+    /// The number of jobs that are queued but not started yet.
+    pub queued_jobs: usize,
+    /// The number of jobs that have finished, including the ones that panicked.
+    pub completed_jobs: u64,
+    /// The running workers ordered by id, including the reserved ones.
+    pub per_worker: Vec<WorkerStats>,
+}
+
+/// A snapshot of a worker of a [`ThreadPool`], see [`Stats::per_worker`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WorkerStats {
+    pub id: usize,
+    pub reserved: bool,
+    /// Whether it's running a job.
+    pub busy: bool,
+    /// The number of jobs waiting in the queue it takes jobs from, which all workers share with
+    /// [`Scheduling::Shared`].
+    pub queued_jobs: usize,
+    /// The number of jobs it has finished.
+    pub completed_jobs: u64,
+}
+
+/// Reads the [`Stats`] of a [`ThreadPool`] from other threads, see [`ThreadPool::monitor`].
+#[derive(Clone)]
+pub struct Monitor {
+    state: Arc<State>,
+}
+
+impl Monitor {
+    pub fn stats(&self) -> Stats {
+        self.state.stats()
+    }
+}
+
+impl fmt::Debug for Monitor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Monitor").field(&self.stats()).finish()
+    }
+}
+
+/// A pool of worker threads that run jobs in the background.
+///
+/// With [`Scheduling::Shared`], the pool is elastic: it spawns another worker when jobs have kept
+/// waiting for a while because all workers are busy, up to [`Builder::max_workers`], so that a
+/// short burst of jobs doesn't spawn any. A worker retires after
+/// being idle for [`Builder::keep_alive`], down to [`Builder::min_workers`]. Idle workers take
+/// turns to wait on the shared queue, so they retire one at a time.
 pub struct ThreadPool {
-    workers: Vec<Worker>,
+    // All running worker threads by id, including the ones that are retiring
+    workers: HashMap<usize, Worker>,
+    next_id: usize,
+    queues: Vec<Queue>,
+    high_priority: Option<Queue>,
+    scheduling: Scheduling,
+    queue_capacity: Option<usize>,
     active_id: usize,
+    // Since when the jobs have found no idle worker, see `grow_if_busy`
+    busy_since: Option<Instant>,
+    state: Arc<State>,
+    // Each worker sends its id and whether it panicked when it exits
+    exited: Receiver<(usize, bool)>,
+    exited_sender: Sender<(usize, bool)>,
+}
+
+/// The state shared by a [`ThreadPool`] and its workers.
+struct State {
+    // When set, the queued jobs are dropped without running
+    abandoned: AtomicBool,
+    // The number of workers that haven't decided to exit, except the reserved ones
+    workers: AtomicUsize,
+    idle_workers: AtomicUsize,
+    reserved_workers: usize,
+    min_workers: AtomicUsize,
+    max_workers: AtomicUsize,
+    keep_alive: Duration,
+    completed_jobs: AtomicU64,
+    // The number of jobs in the high-priority queue
+    high_priority_jobs: Arc<AtomicUsize>,
+    // The running workers by id, each removes itself when its thread exits
+    statuses: Mutex<BTreeMap<usize, Status>>,
+}
+
+/// What a running worker is doing, see [`WorkerStats`].
+struct Status {
+    kind: Kind,
+    // The number of jobs in its queue
+    pending: Arc<AtomicUsize>,
+    activity: Arc<Activity>,
+}
+
+#[derive(Default)]
+struct Activity {
+    busy: AtomicBool,
+    completed_jobs: AtomicU64,
+}
+
+impl State {
+    /// Decides whether a worker should retire because there are more than `limit` workers.
+    fn retire_above(&self, limit: &AtomicUsize) -> bool {
+        let limit = limit.load(Ordering::Acquire);
+        self.workers
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |workers| {
+                (workers > limit).then(|| workers - 1)
+            })
+            .is_ok()
+    }
+
+    fn stats(&self) -> Stats {
+        let statuses = self.statuses.lock().unwrap();
+        // Count each queue once, even if it's shared by workers
+        let mut queues = vec![&self.high_priority_jobs];
+        let per_worker = statuses
+            .iter()
+            .map(|(&id, status)| {
+                if !queues
+                    .iter()
+                    .any(|queue| Arc::ptr_eq(queue, &status.pending))
+                {
+                    queues.push(&status.pending);
+                }
+                WorkerStats {
+                    id,
+                    reserved: status.kind == Kind::Reserved,
+                    busy: status.activity.busy.load(Ordering::Acquire),
+                    queued_jobs: status.pending.load(Ordering::Acquire),
+                    completed_jobs: status.activity.completed_jobs.load(Ordering::Acquire),
+                }
+            })
+            .collect();
+        Stats {
+            workers: self.workers.load(Ordering::Acquire),
+            idle_workers: self.idle_workers.load(Ordering::Acquire),
+            reserved_workers: self.reserved_workers,
+            min_workers: self.min_workers.load(Ordering::Acquire),
+            max_workers: self.max_workers.load(Ordering::Acquire),
+            queued_jobs: queues
+                .iter()
+                .map(|queue| queue.load(Ordering::Acquire))
+                .sum(),
+            completed_jobs: self.completed_jobs.load(Ordering::Acquire),
+            per_worker,
+        }
+    }
 }
 
 impl ThreadPool {
     pub fn new(num_workers: usize) -> Self {
-        let mut workers: Vec<Worker> = vec![];
-        for i in 0..num_workers {
-            let worker = Worker::new(i);
-            log!("Worker {} created", i);
-            workers.push(worker);
-        }
-        ThreadPool {
-            workers: workers,
-            active_id: 0,
-        }
+        Self::with_scheduling(num_workers, Scheduling::default())
+    }
+
+    pub fn with_scheduling(num_workers: usize, scheduling: Scheduling) -> Self {
+        Builder::new(num_workers).scheduling(scheduling).build()
     }
 
     pub fn execute<F>(&mut self, f: F)
     where
         F: FnOnce() + Send + 'static,
     {
-        let index = self.active_id % self.workers.len();
-        self.active_id = index + 1;
-        self.workers[index].sender.send(Box::new(f)).unwrap();
+        self.execute_job(Priority::Normal, Box::new(f));
     }
 
+    /// Like [`ThreadPool::execute`], but a [`Priority::High`] job goes to a separate queue, which
+    /// the reserved workers wait on and the other workers check before taking their next job.
+    pub fn execute_with_priority<F>(&mut self, priority: Priority, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_job(priority, Box::new(f));
+    }
+
+    fn execute_job(&mut self, priority: Priority, job: Job) {
+        self.reap_exited_workers();
+        match priority {
+            Priority::High => {
+                // It blocks when the queue is full
+                self.high_priority.as_ref().unwrap().push(job);
+                self.wake_up();
+            }
+            Priority::Normal => {
+                self.grow_if_busy();
+                let index = self.active_id % self.queues.len();
+                self.active_id = index + 1;
+                // It blocks when the queue is full
+                self.queues[index].push(job);
+            }
+        }
+    }
+
+    /// Like [`ThreadPool::execute`], but returns a handle to wait for the result of `f`, e.g.
+    ///
+    /// ```
+    /// use web_server::thread_pool::ThreadPool;
+    ///
+    /// let mut pool = ThreadPool::new(2);
+    /// let handle = pool.submit(|| 1 + 1);
+    /// assert_eq!(2, handle.join().unwrap());
+    /// ```
+    pub fn submit<F, T>(&mut self, f: F) -> JobHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let packet = Arc::new(Packet::new(None));
+        let task = Task {
+            f: Some(f),
+            packet: packet.clone(),
+            _running: None,
+        };
+        self.execute(move || task.run());
+        JobHandle { packet }
+    }
+
+    /// Runs `f` with a [`Scope`] to submit jobs that borrow from the caller's stack, like
+    /// [`std::thread::scope`]. It returns after all jobs submitted in the scope have finished,
+    /// e.g.
+    ///
+    /// ```
+    /// use web_server::thread_pool::ThreadPool;
+    ///
+    /// let mut pool = ThreadPool::new(2);
+    /// let mut numbers = vec![1, 2, 3, 4];
+    /// let sum = pool.scope(|s| {
+    ///     let (left, right) = numbers.split_at_mut(2);
+    ///     let left = s.submit(|| left.iter_mut().map(|x| *x * 10).sum::<i32>());
+    ///     right.iter_mut().for_each(|x| *x *= 10);
+    ///     left.join().unwrap()
+    /// });
+    /// assert_eq!(30, sum);
+    /// assert_eq!(vec![1, 2, 30, 40], numbers);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `f` panics, or if a job panicked and its handle wasn't joined.
+    pub fn scope<'env, F, R>(&'env mut self, f: F) -> R
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> R,
+    {
+        let scope = Scope {
+            pool: Mutex::new(self),
+            state: Arc::new(ScopeState {
+                running: Mutex::new(0),
+                finished: Condvar::new(),
+                panicked: AtomicBool::new(false),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        // The jobs might borrow anything that outlives the scope, so wait even if `f` panicked
+        let mut running = scope.state.running.lock().unwrap();
+        while *running > 0 {
+            running = scope.state.finished.wait(running).unwrap();
+        }
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if scope.state.panicked.load(Ordering::Acquire) => {
+                panic!("a scoped job panicked")
+            }
+            Ok(result) => result,
+        }
+    }
+
+    /// Like [`ThreadPool::execute`], but returns the job back instead of waiting when the queues
+    /// are full. With [`Scheduling::RoundRobin`], the job goes to the next worker whose queue is
+    /// not full.
+    pub fn try_execute<F>(&mut self, f: F) -> Result<(), Full<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.try_execute_with_priority(Priority::Normal, f)
+    }
+
+    /// Like [`ThreadPool::try_execute`], but with a priority, see
+    /// [`ThreadPool::execute_with_priority`].
+    pub fn try_execute_with_priority<F>(&mut self, priority: Priority, f: F) -> Result<(), Full<F>>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.reap_exited_workers();
+        if priority == Priority::High {
+            let queue = self.high_priority.as_ref().unwrap();
+            if self
+                .queue_capacity
+                .is_some_and(|capacity| queue.jobs.pending.load(Ordering::Acquire) >= capacity)
+            {
+                return Err(Full(f));
+            }
+            queue.push(Box::new(f));
+            self.wake_up();
+            return Ok(());
+        }
+        self.grow_if_busy();
+        for i in 0..self.queues.len() {
+            let index = (self.active_id + i) % self.queues.len();
+            let queue = &self.queues[index];
+            // Only this method and `execute` add jobs, and the signals to the workers have room of
+            // their own, so the queue can't become full meanwhile
+            if self
+                .queue_capacity
+                .is_none_or(|capacity| queue.jobs.pending.load(Ordering::Acquire) < capacity)
+            {
+                self.active_id = index + 1;
+                queue.push(Box::new(f));
+                return Ok(());
+            }
+        }
+        Err(Full(f))
+    }
+
+    /// Fixes the number of workers at `n`: both the minimum and the maximum number of workers
+    /// become `n`, whatever the current size, so an elastic pool stops growing and shrinking.
+    ///
+    /// Extra workers finish their current job before retiring. With
+    /// [`Scheduling::RoundRobin`], the queues of the extra workers are closed and they retire
+    /// after running the jobs already queued there.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0.
+    pub fn resize(&mut self, n: usize) {
+        assert!(n > 0, "a thread pool needs at least one worker");
+        self.reap_exited_workers();
+        let state = &self.state;
+        let workers = match self.scheduling {
+            // The retiring workers don't count since they have no queue
+            Scheduling::RoundRobin => self.queues.len(),
+            Scheduling::Shared => state.workers.load(Ordering::Acquire),
+        };
+        log!("Resizing the thread pool from {} to {} workers", workers, n);
+        state.min_workers.store(n, Ordering::Release);
+        state.max_workers.store(n, Ordering::Release);
+        match self.scheduling {
+            Scheduling::RoundRobin => {
+                // Dropping the senders lets the extra workers exit once their queues are empty
+                self.queues.truncate(n);
+            }
+            Scheduling::Shared => {
+                // Wake up the idle workers to retire, the busy ones retire after their jobs
+                for _ in n..workers {
+                    self.queues[0].signal(Message::Retire);
+                }
+            }
+        }
+        for _ in workers..n {
+            self.spawn_worker();
+        }
+    }
+
+    /// Sets the minimum number of workers to `n` and spawns workers up to it, raising the maximum
+    /// to `n` if it's lower. Unlike [`ThreadPool::resize`], an elastic pool keeps growing up to
+    /// its maximum, and the workers above `n` retire once idle for [`Builder::keep_alive`]. With
+    /// [`Scheduling::RoundRobin`], the size is fixed so it's resized to `n`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0.
+    pub fn set_min_workers(&mut self, n: usize) {
+        if self.scheduling == Scheduling::RoundRobin {
+            self.resize(n);
+            return;
+        }
+        assert!(n > 0, "a thread pool needs at least one worker");
+        self.reap_exited_workers();
+        let state = &self.state;
+        log!("Setting the minimum number of workers to {}", n);
+        state.min_workers.store(n, Ordering::Release);
+        state.max_workers.fetch_max(n, Ordering::AcqRel);
+        for _ in state.workers.load(Ordering::Acquire)..n {
+            self.spawn_worker();
+        }
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.state.stats()
+    }
+
+    /// Returns a handle to read the stats while the pool is owned by another thread, e.g. by a
+    /// running server.
+    pub fn monitor(&self) -> Monitor {
+        Monitor {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Spawns a worker on a new queue with [`Scheduling::RoundRobin`], or on the shared queue.
+    fn spawn_worker(&mut self) {
+        if self.queues.is_empty() || self.scheduling == Scheduling::RoundRobin {
+            self.queues.push(Queue::new(self.queue_capacity));
+        }
+        self.state.workers.fetch_add(1, Ordering::AcqRel);
+        let kind = match self.scheduling {
+            Scheduling::RoundRobin => Kind::Fixed,
+            Scheduling::Shared => Kind::Elastic,
+        };
+        let jobs = self.queues.last().unwrap().jobs.clone();
+        let high_priority = self.high_priority.as_ref().map(|queue| queue.jobs.clone());
+        self.add_worker(kind, jobs, high_priority);
+    }
+
+    fn add_worker(&mut self, kind: Kind, jobs: Jobs, high_priority: Option<Jobs>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let worker = self.start_worker(id, kind, jobs, high_priority);
+        self.workers.insert(id, worker);
+        log!("Worker {} created", id);
+    }
+
+    fn start_worker(
+        &self,
+        id: usize,
+        kind: Kind,
+        jobs: Jobs,
+        high_priority: Option<Jobs>,
+    ) -> Worker {
+        Worker::new(
+            id,
+            kind,
+            jobs,
+            high_priority,
+            self.state.clone(),
+            self.exited_sender.clone(),
+        )
+    }
+
+    /// Lets an idle worker check the high-priority queue in case no reserved worker is idle.
+    fn wake_up(&self) {
+        self.queues[self.active_id % self.queues.len()].signal(Message::Wake);
+    }
+
+    /// Spawns another worker if the jobs, including the next one, have found no idle worker for
+    /// [`GROW_DELAY`], i.e. the queue has stayed busy rather than taken a short burst.
+    fn grow_if_busy(&mut self) {
+        if self.scheduling != Scheduling::Shared {
+            return;
+        }
+        let state = &self.state;
+        if self.queues[0].jobs.pending.load(Ordering::Acquire)
+            < state.idle_workers.load(Ordering::Acquire)
+        {
+            self.busy_since = None;
+            return;
+        }
+        let now = Instant::now();
+        let busy_since = *self.busy_since.get_or_insert(now);
+        if now - busy_since >= GROW_DELAY
+            && state.workers.load(Ordering::Acquire) < state.max_workers.load(Ordering::Acquire)
+        {
+            // Another worker has to stay busy as long before the next one
+            self.busy_since = Some(now);
+            self.spawn_worker();
+        }
+    }
+
+    /// Joins the workers that exited, and replaces the ones that died so that the pool never
+    /// shrinks by accident.
+    ///
+    /// A panic in a job is caught by the worker, so a worker only dies when a panic escapes from
+    /// that, e.g. when dropping the panic payload panics again.
+    fn reap_exited_workers(&mut self) {
+        while let Ok((id, panicked)) = self.exited.try_recv() {
+            let Some(worker) = self.workers.remove(&id) else {
+                continue;
+            };
+            let _ = worker.handle.join();
+            if panicked {
+                // Keep serving the same queue, which might be closed by `resize` already
+                let worker = self.start_worker(id, worker.kind, worker.jobs, worker.high_priority);
+                self.workers.insert(id, worker);
+                log!("Worker {} died and has been respawned", id);
+            }
+        }
+    }
+
+    /// Stops accepting jobs and waits until all queued and running jobs are done.
     pub fn shutdown(&mut self) {
-        let workers = self.workers.drain(..).collect::<Vec<_>>();
-        for (i, worker) in workers.into_iter().enumerate() {
-            log!("Shutting down worker {}", i);
-            worker.close();
+        self.drain(None);
+    }
+
+    /// Stops accepting jobs and waits until all queued and running jobs are done, or `timeout`
+    /// expires. In the latter case, the jobs that haven't started are dropped without running and
+    /// the busy workers are detached.
+    ///
+    /// Returns the number of abandoned jobs.
+    pub fn shutdown_timeout(&mut self, timeout: Duration) -> usize {
+        self.drain(Some(Instant::now() + timeout))
+    }
+
+    fn drain(&mut self, deadline: Option<Instant>) -> usize {
+        // Dropping the senders lets the workers exit once the queues are empty
+        self.queues.clear();
+        self.high_priority = None;
+        let mut workers = HashMap::new();
+        for (id, worker) in self.workers.drain() {
+            log!("Shutting down worker {}", id);
+            workers.insert(id, worker);
+        }
+        while !workers.is_empty() {
+            let id = match deadline {
+                Some(deadline) => match self
+                    .exited
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                {
+                    Ok((id, _)) => id,
+                    Err(_) => break,
+                },
+                None => match self.exited.recv() {
+                    Ok((id, _)) => id,
+                    Err(_) => break,
+                },
+            };
+            if let Some(worker) = workers.remove(&id) {
+                let _ = worker.handle.join();
+            }
+        }
+        if workers.is_empty() {
+            return 0;
+        }
+
+        self.state.abandoned.store(true, Ordering::Release);
+        let mut ids = workers.keys().collect::<Vec<_>>();
+        ids.sort();
+        for id in ids {
+            log!("Worker {} is still busy after the deadline", id);
+        }
+        // Workers sharing a queue hold the same counter
+        let mut pending: Vec<&Arc<AtomicUsize>> = vec![];
+        for jobs in workers
+            .values()
+            .flat_map(|worker| [Some(&worker.jobs), worker.high_priority.as_ref()])
+            .flatten()
+        {
+            if !pending.iter().any(|p| Arc::ptr_eq(p, &jobs.pending)) {
+                pending.push(&jobs.pending);
+            }
+        }
+        let abandoned_jobs = pending
+            .iter()
+            .map(|pending| pending.load(Ordering::Acquire))
+            .sum();
+        log!("Abandoning {} queued jobs", abandoned_jobs);
+        abandoned_jobs
+    }
+}
+
+/// Why a job submitted by [`ThreadPool::submit`] or [`Scope::submit`] has no result.
+pub enum JoinError {
+    /// The job panicked with this payload.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The job was dropped without running by [`ThreadPool::shutdown_timeout`].
+    Abandoned,
+}
+
+impl fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(payload) => f
+                .debug_tuple("Panicked")
+                .field(&panic_message(payload.as_ref()))
+                .finish(),
+            JoinError::Abandoned => f.write_str("Abandoned"),
+        }
+    }
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JoinError::Panicked(payload) => {
+                write!(f, "the job panicked: {}", panic_message(payload.as_ref()))
+            }
+            JoinError::Abandoned => f.write_str("the job was abandoned"),
+        }
+    }
+}
+
+impl std::error::Error for JoinError {}
+
+/// A handle to wait for the result of a job submitted by [`ThreadPool::submit`].
+pub struct JobHandle<T> {
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JobHandle<T> {
+    /// Waits for the job to finish and returns its result.
+    pub fn join(self) -> Result<T, JoinError> {
+        self.packet.wait()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.result.lock().unwrap().is_some()
+    }
+}
+
+/// Submits jobs that can borrow anything outliving the scope, see [`ThreadPool::scope`].
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: Mutex<&'env mut ThreadPool>,
+    state: Arc<ScopeState>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+struct ScopeState {
+    // The number of jobs that haven't finished or been abandoned yet
+    running: Mutex<usize>,
+    finished: Condvar,
+    // Set when a job panicked and its handle wasn't joined
+    panicked: AtomicBool,
+}
+
+impl<'scope> Scope<'scope, '_> {
+    /// Like [`ThreadPool::submit`], but `f` only needs to outlive the scope.
+    pub fn submit<F, T>(&'scope self, f: F) -> ScopedJobHandle<'scope, T>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        *self.state.running.lock().unwrap() += 1;
+        let packet = Arc::new(Packet::new(Some(self.state.clone())));
+        let task = Task {
+            f: Some(f),
+            packet: packet.clone(),
+            _running: Some(Running(self.state.clone())),
+        };
+        let job: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || task.run());
+        // SAFETY: `ThreadPool::scope` doesn't return until the task is dropped, which happens
+        // after the job and everything it borrows are dropped, whether it ran or not. The result
+        // is dropped with the task too, or by the handle, which can't outlive the scope unless
+        // it's leaked.
+        let job = unsafe { mem::transmute::<Box<dyn FnOnce() + Send + 'scope>, Job>(job) };
+        self.pool.lock().unwrap().execute_job(Priority::Normal, job);
+        ScopedJobHandle {
+            packet,
+            scope: PhantomData,
+        }
+    }
+}
+
+/// A handle to wait for the result of a job submitted by [`Scope::submit`].
+pub struct ScopedJobHandle<'scope, T> {
+    packet: Arc<Packet<T>>,
+    scope: PhantomData<&'scope ()>,
+}
+
+impl<T> ScopedJobHandle<'_, T> {
+    /// Waits for the job to finish and returns its result.
+    pub fn join(self) -> Result<T, JoinError> {
+        self.packet.wait()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.packet.result.lock().unwrap().is_some()
+    }
+}
+
+/// Where a job puts its result for the handle.
+struct Packet<T> {
+    result: Mutex<Option<Result<T, JoinError>>>,
+    finished: Condvar,
+    scope: Option<Arc<ScopeState>>,
+}
+
+impl<T> Packet<T> {
+    fn new(scope: Option<Arc<ScopeState>>) -> Self {
+        Packet {
+            result: Mutex::new(None),
+            finished: Condvar::new(),
+            scope,
+        }
+    }
+
+    fn set(&self, result: Result<T, JoinError>) {
+        *self.result.lock().unwrap() = Some(result);
+        self.finished.notify_all();
+    }
+
+    fn wait(&self) -> Result<T, JoinError> {
+        let mut result = self.result.lock().unwrap();
+        loop {
+            match result.take() {
+                Some(result) => return result,
+                None => result = self.finished.wait(result).unwrap(),
+            }
+        }
+    }
+}
+
+impl<T> Drop for Packet<T> {
+    fn drop(&mut self) {
+        let Some(scope) = &self.scope else {
+            return;
+        };
+        let result = self
+            .result
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        if let Some(Err(JoinError::Panicked(_))) = result {
+            scope.panicked.store(true, Ordering::Release);
+        }
+        // The result might borrow from the scope as well, so it must not unwind the worker
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(result)));
+    }
+}
+
+/// Counts a job as running in its scope until dropped.
+struct Running(Arc<ScopeState>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        *self.0.running.lock().unwrap() -= 1;
+        self.0.finished.notify_all();
+    }
+}
+
+/// A job whose result goes to a [`Packet`], even if it panics or never runs.
+struct Task<F, T> {
+    // Fields are dropped in order, so `f` and whatever it borrows, then the packet if the handle
+    // is gone, are dropped before the scope can end
+    f: Option<F>,
+    packet: Arc<Packet<T>>,
+    _running: Option<Running>,
+}
+
+impl<F: FnOnce() -> T, T> Task<F, T> {
+    fn run(mut self) {
+        let f = self.f.take().unwrap();
+        let result = panic::catch_unwind(AssertUnwindSafe(f)).map_err(JoinError::Panicked);
+        self.packet.set(result);
+    }
+}
+
+impl<F, T> Drop for Task<F, T> {
+    fn drop(&mut self) {
+        if let Some(f) = self.f.take() {
+            drop(f);
+            self.packet.set(Err(JoinError::Abandoned));
         }
     }
 }
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// The room for signals in a bounded queue on top of its capacity for jobs.
+const MAX_SIGNALS: usize = 16;
+
+/// How long the jobs must have found no idle worker before an elastic pool grows.
+const GROW_DELAY: Duration = Duration::from_millis(10);
+
+enum Message {
+    Job(Job),
+    /// Asks a worker to check the high-priority queue.
+    Wake,
+    /// Asks a worker to retire if there are more workers than the maximum.
+    Retire,
+}
+
+/// A queue of jobs taken by one worker, or by all workers with [`Scheduling::Shared`].
+struct Queue {
+    sender: MessageSender,
+    jobs: Jobs,
+}
+
+/// The receiving side of a [`Queue`].
+#[derive(Clone)]
+struct Jobs {
+    receiver: Arc<Mutex<Receiver<Message>>>,
+    // The number of jobs that are queued but not started yet
+    pending: Arc<AtomicUsize>,
+    // The number of messages other than jobs that are queued
+    signals: Arc<AtomicUsize>,
+}
+
+enum MessageSender {
+    Unbounded(Sender<Message>),
+    Bounded(SyncSender<Message>),
+}
+
+impl Queue {
+    fn new(capacity: Option<usize>) -> Self {
+        let (sender, receiver) = match capacity {
+            Some(capacity) => {
+                let (sender, receiver) = sync_channel::<Message>(capacity + MAX_SIGNALS);
+                (MessageSender::Bounded(sender), receiver)
+            }
+            None => {
+                let (sender, receiver) = channel::<Message>();
+                (MessageSender::Unbounded(sender), receiver)
+            }
+        };
+        Queue {
+            sender,
+            jobs: Jobs {
+                receiver: Arc::new(Mutex::new(receiver)),
+                pending: Arc::new(AtomicUsize::new(0)),
+                signals: Arc::new(AtomicUsize::new(0)),
+            },
+        }
+    }
+
+    fn push(&self, job: Job) {
+        self.jobs.pending.fetch_add(1, Ordering::AcqRel);
+        let result: Result<(), SendError<Message>> = match &self.sender {
+            MessageSender::Unbounded(sender) => sender.send(Message::Job(job)),
+            MessageSender::Bounded(sender) => sender.send(Message::Job(job)),
+        };
+        // The queue owns the receiver, so it's never disconnected even if the workers died
+        result.unwrap();
+    }
+
+    /// Sends a message other than a job, unless [`MAX_SIGNALS`] are queued already.
+    ///
+    /// The signals never take the room of the jobs, so that [`Queue::push`] only blocks when
+    /// there are as many jobs as the capacity.
+    fn signal(&self, message: Message) {
+        // So many signals mean the workers are busy, and they check the high-priority queue and
+        // whether to retire after their jobs anyway
+        let signals = &self.jobs.signals;
+        if signals.load(Ordering::Acquire) >= MAX_SIGNALS {
+            return;
+        }
+        signals.fetch_add(1, Ordering::AcqRel);
+        let result = match &self.sender {
+            MessageSender::Unbounded(sender) => sender.send(message).is_ok(),
+            MessageSender::Bounded(sender) => sender.try_send(message).is_ok(),
+        };
+        if !result {
+            signals.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
 struct Worker {
-    sender: Sender<Job>,
     handle: JoinHandle<()>,
-    running: Arc<AtomicBool>,
+    kind: Kind,
+    jobs: Jobs,
+    high_priority: Option<Jobs>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// Takes jobs from its own queue with [`Scheduling::RoundRobin`].
+    Fixed,
+    /// Takes jobs from the shared queue, and retires when it has been idle for the keep-alive
+    /// interval or when there are too many workers.
+    Elastic,
+    /// Only takes jobs from the high-priority queue.
+    Reserved,
+}
+
+/// Tells the pool that the worker thread exited, even by a panic.
+struct ExitGuard {
+    id: usize,
+    state: Arc<State>,
+    exited: Sender<(usize, bool)>,
+}
+
+impl Drop for ExitGuard {
+    fn drop(&mut self) {
+        // Before the pool learns about it, which might respawn a worker with the same id
+        self.state.statuses.lock().unwrap().remove(&self.id);
+        let _ = self.exited.send((self.id, thread::panicking()));
+    }
 }
 
 impl Worker {
-    fn new(id: usize) -> Self {
-        let (sender, receiver) = channel::<Job>();
-        let running = Arc::new(AtomicBool::new(true));
-        let running_clone = running.clone();
+    /// Starts a worker that takes jobs from `jobs`, and first from `high_priority` if any.
+    fn new(
+        id: usize,
+        kind: Kind,
+        jobs: Jobs,
+        high_priority: Option<Jobs>,
+        state: Arc<State>,
+        exited: Sender<(usize, bool)>,
+    ) -> Self {
+        let Jobs {
+            receiver,
+            pending,
+            signals,
+        } = jobs.clone();
+        let high = high_priority.clone();
+        // The reserved workers don't count, so that the pool doesn't resize itself for them
+        let counted = kind != Kind::Reserved;
+        let activity = Arc::new(Activity::default());
+        state.statuses.lock().unwrap().insert(
+            id,
+            Status {
+                kind,
+                pending: pending.clone(),
+                activity: activity.clone(),
+            },
+        );
         let handle = thread::Builder::new()
             .name(format!("worker-{}", id))
             .spawn(move || {
+                let _guard = ExitGuard {
+                    id,
+                    state: state.clone(),
+                    exited,
+                };
                 loop {
-                    match receiver.recv() {
-                        Ok(job) => {
-                            if !running_clone.load(std::sync::atomic::Ordering::Acquire) {
-                                log!("Worker {} is shutting down", id);
-                                return;
+                    // A locked high-priority queue means a reserved worker is waiting for it
+                    if let Some(high) = &high {
+                        let message = match high.receiver.try_lock() {
+                            Ok(receiver) => receiver.try_recv().ok(),
+                            Err(_) => None,
+                        };
+                        if let Some(Message::Job(job)) = message {
+                            high.pending.fetch_sub(1, Ordering::AcqRel);
+                            run_job(id, job, &state, &activity);
+                            continue;
+                        }
+                    }
+                    if counted {
+                        state.idle_workers.fetch_add(1, Ordering::AcqRel);
+                    }
+                    // The guard is dropped before running the job so that other workers can
+                    // take jobs from a shared queue meanwhile
+                    let message = if kind == Kind::Elastic {
+                        receiver.lock().unwrap().recv_timeout(state.keep_alive)
+                    } else {
+                        receiver
+                            .lock()
+                            .unwrap()
+                            .recv()
+                            .map_err(|_| RecvTimeoutError::Disconnected)
+                    };
+                    if counted {
+                        state.idle_workers.fetch_sub(1, Ordering::AcqRel);
+                    }
+                    match message {
+                        Ok(Message::Job(job)) => {
+                            pending.fetch_sub(1, Ordering::AcqRel);
+                            run_job(id, job, &state, &activity);
+                            if kind == Kind::Elastic && state.retire_above(&state.max_workers) {
+                                log!("Worker {} retired; too many workers.", id);
+                                break;
+                            }
+                        }
+                        Ok(Message::Wake) => {
+                            signals.fetch_sub(1, Ordering::AcqRel);
+                        }
+                        Ok(Message::Retire) => {
+                            signals.fetch_sub(1, Ordering::AcqRel);
+                            if state.retire_above(&state.max_workers) {
+                                log!("Worker {} retired; too many workers.", id);
+                                break;
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => {
+                            if state.retire_above(&state.min_workers) {
+                                log!("Worker {} retired; idle for too long.", id);
+                                break;
                             }
-                            log!("Worker {} received a job", id);
-                            job();
                         }
-                        Err(_) => {
+                        Err(RecvTimeoutError::Disconnected) => {
                             log!("Worker {} disconnected; exiting.", id);
+                            if counted {
+                                state.workers.fetch_sub(1, Ordering::AcqRel);
+                            }
                             break;
                         }
                     }
@@ -94,16 +1184,466 @@ impl Worker {
             })
             .unwrap();
         Worker {
-            sender,
             handle,
-            running,
+            kind,
+            jobs,
+            high_priority,
+        }
+    }
+}
+
+fn run_job(id: usize, job: Job, state: &State, activity: &Activity) {
+    if state.abandoned.load(Ordering::Acquire) {
+        return;
+    }
+    log!("Worker {} received a job", id);
+    activity.busy.store(true, Ordering::Release);
+    if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(job)) {
+        log!(
+            "Worker {} caught a panic in a job: {}",
+            id,
+            panic_message(payload.as_ref())
+        );
+    }
+    activity.busy.store(false, Ordering::Release);
+    activity.completed_jobs.fetch_add(1, Ordering::AcqRel);
+    state.completed_jobs.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Returns the message of a panic payload created by `panic!`.
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shutdown_runs_queued_jobs() {
+        for scheduling in [Scheduling::RoundRobin, Scheduling::Shared] {
+            let mut pool = ThreadPool::with_scheduling(2, scheduling);
+            let count = Arc::new(AtomicUsize::new(0));
+            for _ in 0..10 {
+                let count = count.clone();
+                pool.execute(move || {
+                    thread::sleep(Duration::from_millis(5));
+                    count.fetch_add(1, Ordering::AcqRel);
+                });
+            }
+            pool.shutdown();
+            assert_eq!(10, count.load(Ordering::Acquire));
         }
     }
 
-    fn close(self) {
-        self.running
-            .store(false, std::sync::atomic::Ordering::Release);
-        self.sender.send(Box::new(|| {})).unwrap();
-        self.handle.join().unwrap();
+    #[test]
+    fn survive_panics() {
+        // Dropping this panic payload panics again, which kills the worker
+        struct PanicOnDrop;
+
+        impl Drop for PanicOnDrop {
+            fn drop(&mut self) {
+                panic!("panic on drop");
+            }
+        }
+
+        for scheduling in [Scheduling::RoundRobin, Scheduling::Shared] {
+            let mut pool = ThreadPool::with_scheduling(2, scheduling);
+            pool.execute(|| panic!("panic in a job"));
+            pool.execute(|| panic::panic_any(PanicOnDrop));
+            thread::sleep(Duration::from_millis(50));
+
+            let count = Arc::new(AtomicUsize::new(0));
+            for _ in 0..4 {
+                let count = count.clone();
+                pool.execute(move || {
+                    count.fetch_add(1, Ordering::AcqRel);
+                });
+            }
+            pool.shutdown();
+            assert_eq!(4, count.load(Ordering::Acquire));
+        }
+    }
+
+    #[test]
+    fn submit() {
+        let mut pool = ThreadPool::new(1);
+        let handle = pool.submit(|| {
+            thread::sleep(Duration::from_millis(100));
+            "done".to_string()
+        });
+        let panicked = pool.submit(|| -> i32 { panic!("oops") });
+        let queued = pool.submit(|| 1);
+        assert!(!handle.is_finished());
+        assert_eq!("done", handle.join().unwrap());
+        match panicked.join() {
+            Err(JoinError::Panicked(payload)) => {
+                assert_eq!("oops", panic_message(payload.as_ref()))
+            }
+            result => panic!("unexpected result: {:?}", result),
+        }
+
+        let handle = pool.submit(|| thread::sleep(Duration::from_millis(200)));
+        let abandoned_too = pool.submit(|| 2);
+        assert_eq!(1, queued.join().unwrap());
+        assert_eq!(1, pool.shutdown_timeout(Duration::from_millis(50)));
+        assert!(matches!(abandoned_too.join(), Err(JoinError::Abandoned)));
+        assert!(handle.join().is_ok());
+    }
+
+    #[test]
+    fn scope() {
+        let mut pool = ThreadPool::with_scheduling(4, Scheduling::Shared);
+        let mut numbers = (1..=100).collect::<Vec<u64>>();
+        let total = pool.scope(|s| {
+            let handles = numbers
+                .chunks_mut(10)
+                .map(|chunk| {
+                    s.submit(move || {
+                        chunk.iter_mut().for_each(|x| *x *= 2);
+                        chunk.iter().sum::<u64>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles.into_iter().map(|h| h.join().unwrap()).sum::<u64>()
+        });
+        assert_eq!(10100, total);
+        assert_eq!(200, numbers[99]);
+
+        // Jobs that are never joined still finish before the scope returns
+        let count = AtomicUsize::new(0);
+        pool.scope(|s| {
+            for _ in 0..8 {
+                s.submit(|| {
+                    thread::sleep(Duration::from_millis(10));
+                    count.fetch_add(1, Ordering::AcqRel);
+                });
+            }
+        });
+        assert_eq!(8, count.load(Ordering::Acquire));
+
+        // So do jobs whose handles are leaked
+        pool.scope(|s| {
+            mem::forget(s.submit(|| {
+                thread::sleep(Duration::from_millis(10));
+                count.fetch_add(1, Ordering::AcqRel);
+            }));
+        });
+        assert_eq!(9, count.load(Ordering::Acquire));
+
+        // A panic is only propagated if nobody joined the job
+        pool.scope(|s| assert!(s.submit(|| panic!("joined")).join().is_err()));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|s| {
+                s.submit(|| panic!("not joined"));
+            })
+        }));
+        assert!(result.is_err());
+        pool.shutdown();
+    }
+
+    #[test]
+    fn high_priority_jobs_jump_the_queue() {
+        let mut pool = ThreadPool::new(1);
+        let (sender, receiver) = channel();
+        pool.execute(|| thread::sleep(Duration::from_millis(100)));
+        for i in 0..3 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(i).unwrap());
+        }
+        let sender_clone = sender.clone();
+        pool.execute_with_priority(Priority::High, move || sender_clone.send(-1).unwrap());
+        assert_eq!(
+            vec![-1, 0, 1, 2],
+            receiver.iter().take(4).collect::<Vec<_>>()
+        );
+
+        // An idle worker is woken up for a high-priority job
+        pool.execute_with_priority(Priority::High, move || sender.send(-2).unwrap());
+        assert_eq!(
+            -2,
+            receiver.recv_timeout(Duration::from_millis(100)).unwrap()
+        );
+        pool.shutdown();
+    }
+
+    #[test]
+    fn reserved_workers() {
+        let mut pool = Builder::new(1)
+            .scheduling(Scheduling::Shared)
+            .queue_capacity(1)
+            .reserved_workers(1)
+            .build();
+        let (sender, receiver) = channel::<()>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        // Block the only normal worker and fill the queue
+        for _ in 0..2 {
+            let receiver = receiver.clone();
+            pool.execute(move || receiver.lock().unwrap().recv().unwrap());
+        }
+        assert!(pool.try_execute(|| {}).is_err());
+
+        let (high_sender, high_receiver) = channel();
+        assert!(
+            pool.try_execute_with_priority(Priority::High, move || high_sender.send(()).unwrap())
+                .is_ok()
+        );
+        assert!(high_receiver.recv_timeout(Duration::from_secs(1)).is_ok());
+        assert_eq!(1, pool.stats().reserved_workers);
+
+        sender.send(()).unwrap();
+        sender.send(()).unwrap();
+        pool.shutdown();
+    }
+
+    #[test]
+    fn per_worker_stats() {
+        let mut pool = Builder::new(2).reserved_workers(1).build();
+        let (sender, receiver) = channel::<()>();
+        // The first and third jobs go to worker 0, the first one blocks it
+        pool.execute(move || receiver.recv().unwrap());
+        for _ in 0..3 {
+            pool.execute(|| {});
+        }
+        // Read from another thread like a server does
+        let monitor = pool.monitor();
+        let stats = thread::spawn(move || {
+            let start = Instant::now();
+            loop {
+                let stats = monitor.stats();
+                if stats.completed_jobs == 2 && stats.per_worker[0].busy {
+                    return stats;
+                }
+                assert!(start.elapsed() < Duration::from_secs(2), "{:?}", stats);
+                thread::sleep(Duration::from_millis(10));
+            }
+        })
+        .join()
+        .unwrap();
+        let worker = |id, reserved, busy, queued_jobs, completed_jobs| WorkerStats {
+            id,
+            reserved,
+            busy,
+            queued_jobs,
+            completed_jobs,
+        };
+        assert_eq!(
+            vec![
+                worker(0, false, true, 1, 0),
+                worker(1, false, false, 0, 2),
+                worker(2, true, false, 0, 0),
+            ],
+            stats.per_worker
+        );
+        assert_eq!(1, stats.queued_jobs);
+
+        sender.send(()).unwrap();
+        pool.shutdown();
+        assert_eq!(4, pool.stats().completed_jobs);
+        assert!(pool.stats().per_worker.is_empty());
+    }
+
+    /// Waits up to 2 seconds for the number of workers to become `workers`.
+    fn wait_for_workers(pool: &ThreadPool, workers: usize) {
+        let start = Instant::now();
+        while pool.stats().workers != workers {
+            assert!(
+                start.elapsed() < Duration::from_secs(2),
+                "{:?}",
+                pool.stats()
+            );
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn grow_and_retire_idle_workers() {
+        let mut pool = Builder::new(1)
+            .scheduling(Scheduling::Shared)
+            .max_workers(3)
+            .keep_alive(Duration::from_millis(50))
+            .build();
+        // The jobs can only finish if they run at the same time
+        let barrier = Arc::new(std::sync::Barrier::new(4));
+        for _ in 0..3 {
+            let barrier = barrier.clone();
+            pool.execute(move || {
+                barrier.wait();
+            });
+        }
+        // A short burst doesn't grow the pool, jobs that keep waiting do
+        assert_eq!(1, pool.stats().workers);
+        let mut jobs = 3;
+        while pool.stats().workers < 3 {
+            assert!(jobs < 1000, "{:?}", pool.stats());
+            thread::sleep(GROW_DELAY);
+            pool.execute(|| {});
+            jobs += 1;
+        }
+        barrier.wait();
+        assert_eq!(3, pool.stats().workers);
+
+        wait_for_workers(&pool, 1);
+        thread::sleep(Duration::from_millis(200));
+        let stats = pool.stats();
+        assert_eq!(1, stats.workers);
+        assert_eq!(jobs, stats.completed_jobs);
+        pool.shutdown();
+    }
+
+    #[test]
+    fn resize() {
+        for scheduling in [Scheduling::RoundRobin, Scheduling::Shared] {
+            let mut pool = ThreadPool::with_scheduling(4, scheduling);
+            let count = Arc::new(AtomicUsize::new(0));
+            for n in [2, 6, 1] {
+                for _ in 0..10 {
+                    let count = count.clone();
+                    pool.execute(move || {
+                        thread::sleep(Duration::from_millis(5));
+                        count.fetch_add(1, Ordering::AcqRel);
+                    });
+                }
+                pool.resize(n);
+                wait_for_workers(&pool, n);
+                assert_eq!((n, n), (pool.stats().min_workers, pool.stats().max_workers));
+            }
+            pool.shutdown();
+            assert_eq!(30, count.load(Ordering::Acquire));
+        }
+
+        // Growing an elastic pool fixes its size as well, unlike `set_min_workers`
+        let mut pool = Builder::new(2)
+            .scheduling(Scheduling::Shared)
+            .max_workers(8)
+            .build();
+        pool.resize(4);
+        wait_for_workers(&pool, 4);
+        assert_eq!((4, 4), (pool.stats().min_workers, pool.stats().max_workers));
+        pool.shutdown();
+
+        let mut pool = Builder::new(2)
+            .scheduling(Scheduling::Shared)
+            .max_workers(8)
+            .keep_alive(Duration::from_millis(50))
+            .build();
+        pool.set_min_workers(4);
+        assert_eq!(4, pool.stats().workers);
+        assert_eq!((4, 8), (pool.stats().min_workers, pool.stats().max_workers));
+        pool.set_min_workers(1);
+        wait_for_workers(&pool, 1);
+        assert_eq!((1, 8), (pool.stats().min_workers, pool.stats().max_workers));
+        pool.set_min_workers(10);
+        assert_eq!(
+            (10, 10),
+            (pool.stats().min_workers, pool.stats().max_workers)
+        );
+        pool.shutdown();
+    }
+
+    #[test]
+    fn try_execute_when_full() {
+        for scheduling in [Scheduling::RoundRobin, Scheduling::Shared] {
+            let mut pool = Builder::new(2)
+                .scheduling(scheduling)
+                .queue_capacity(1)
+                .build();
+            let (sender, receiver) = channel::<()>();
+            let receiver = Arc::new(Mutex::new(receiver));
+            // Block both workers, then fill the queues
+            for _ in 0..2 {
+                let receiver = receiver.clone();
+                pool.execute(move || receiver.lock().unwrap().recv().unwrap());
+                thread::sleep(Duration::from_millis(50));
+            }
+            let capacity = if scheduling == Scheduling::Shared {
+                1
+            } else {
+                2
+            };
+            for _ in 0..capacity {
+                assert!(pool.try_execute(|| {}).is_ok());
+            }
+            assert!(pool.try_execute(|| {}).is_err());
+
+            sender.send(()).unwrap();
+            sender.send(()).unwrap();
+            pool.shutdown();
+        }
+    }
+
+    #[test]
+    fn resize_when_full() {
+        let mut pool = Builder::new(2)
+            .scheduling(Scheduling::Shared)
+            .queue_capacity(1)
+            .build();
+        let (sender, receiver) = channel::<()>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..2 {
+            let receiver = receiver.clone();
+            pool.execute(move || receiver.lock().unwrap().recv().unwrap());
+        }
+        wait_for_workers(&pool, 2);
+        thread::sleep(Duration::from_millis(50));
+        // The busy workers leave the signals to retire queued, which mustn't take the room of
+        // the jobs and block `try_execute`
+        let (done_sender, done) = channel();
+        let handle = thread::spawn(move || {
+            pool.resize(1);
+            assert!(pool.try_execute(|| {}).is_ok());
+            assert!(pool.try_execute(|| {}).is_err());
+            pool.resize(2);
+            pool.resize(1);
+            assert!(pool.try_execute(|| {}).is_err());
+            done_sender.send(()).unwrap();
+            pool
+        });
+        let result = done.recv_timeout(Duration::from_secs(5));
+        sender.send(()).unwrap();
+        sender.send(()).unwrap();
+        assert!(result.is_ok(), "try_execute blocked");
+        handle.join().unwrap().shutdown();
+    }
+
+    #[test]
+    fn shared_queue_avoids_head_of_line_blocking() {
+        let mut pool = ThreadPool::with_scheduling(2, Scheduling::Shared);
+        let (sender, receiver) = channel();
+        pool.execute(|| thread::sleep(Duration::from_millis(300)));
+        let start = Instant::now();
+        // With round-robin scheduling, the second fast job would wait for the slow one
+        for _ in 0..3 {
+            let sender = sender.clone();
+            pool.execute(move || sender.send(start.elapsed()).unwrap());
+        }
+        for _ in 0..3 {
+            assert!(receiver.recv().unwrap() < Duration::from_millis(200));
+        }
+        pool.shutdown();
+    }
+
+    #[test]
+    fn shutdown_timeout_abandons_queued_jobs() {
+        let mut pool = ThreadPool::new(2);
+        let count = Arc::new(AtomicUsize::new(0));
+        for _ in 0..4 {
+            let count = count.clone();
+            pool.execute(move || {
+                thread::sleep(Duration::from_millis(200));
+                count.fetch_add(1, Ordering::AcqRel);
+            });
+        }
+        let start = Instant::now();
+        assert_eq!(2, pool.shutdown_timeout(Duration::from_millis(50)));
+        assert!(start.elapsed() < Duration::from_millis(150));
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(2, count.load(Ordering::Acquire));
     }
 }
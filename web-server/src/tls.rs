@@ -0,0 +1,189 @@
+// Copyright 2025 Yunze Xu
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use rustls::{
+    crypto::{CryptoProvider, ring},
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use std::{collections::HashMap, fmt, io, path::Path, sync::Arc};
+
+/// The certificates of an HTTPS listener, see [`Server::with_tls`].
+///
+/// Clients that ask for a host name by SNI (Server Name Indication) get the certificate added
+/// for it by [`TlsConfig::with_sni_pem_files`], where `*.example.com` matches any direct
+/// subdomain of `example.com`. Other clients get the default certificate.
+///
+/// [`Server::with_tls`]: crate::server::Server::with_tls
+#[derive(Clone)]
+pub struct TlsConfig {
+    resolver: Arc<SniResolver>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl TlsConfig {
+    /// Loads the default certificate chain and its private key from PEM files, e.g. the
+    /// `fullchain.pem` and `privkey.pem` of Let's Encrypt.
+    pub fn from_pem_files<P: AsRef<Path>>(cert: P, key: P) -> io::Result<Self> {
+        let provider = Arc::new(ring::default_provider());
+        let default = load_certified_key(cert.as_ref(), key.as_ref(), &provider)?;
+        Ok(TlsConfig {
+            resolver: Arc::new(SniResolver {
+                default,
+                by_name: HashMap::new(),
+            }),
+            provider,
+        })
+    }
+
+    /// Loads the certificate chain and private key served to clients that ask for `host_name`.
+    pub fn with_sni_pem_files<P: AsRef<Path>>(
+        mut self,
+        host_name: &str,
+        cert: P,
+        key: P,
+    ) -> io::Result<Self> {
+        let certified_key = load_certified_key(cert.as_ref(), key.as_ref(), &self.provider)?;
+        Arc::make_mut(&mut self.resolver)
+            .by_name
+            .insert(host_name.to_ascii_lowercase(), certified_key);
+        Ok(self)
+    }
+
+    /// Builds the configuration of the connections.
+    pub(crate) fn server_config(&self) -> Arc<rustls::ServerConfig> {
+        let mut config = rustls::ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("the default protocol versions are supported by the provider")
+            .with_no_client_auth()
+            .with_cert_resolver(self.resolver.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Arc::new(config)
+    }
+}
+
+fn load_certified_key(
+    cert: &Path,
+    key: &Path,
+    provider: &CryptoProvider,
+) -> io::Result<Arc<CertifiedKey>> {
+    let invalid = |path: &Path, e: &dyn fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    };
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(cert, &e))?;
+    if chain.is_empty() {
+        return Err(invalid(cert, &"no certificate found"));
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key).map_err(|e| invalid(key, &e))?;
+    // Fails if the key doesn't belong to the certificate
+    let certified_key =
+        CertifiedKey::from_der(chain, private_key, provider).map_err(|e| invalid(key, &e))?;
+    Ok(Arc::new(certified_key))
+}
+
+/// Chooses the certificate by the SNI of the client.
+#[derive(Clone)]
+struct SniResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    fn find(&self, host_name: &str) -> Option<Arc<CertifiedKey>> {
+        let host_name = host_name.to_ascii_lowercase();
+        if let Some(certified_key) = self.by_name.get(&host_name) {
+            return Some(certified_key.clone());
+        }
+        let (_, parent) = host_name.split_once('.')?;
+        self.by_name.get(&format!("*.{}", parent)).cloned()
+    }
+}
+
+impl fmt::Debug for SniResolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SniResolver")
+            .field("host_names", &self.by_name.keys())
+            .finish()
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let found = client_hello
+            .server_name()
+            .and_then(|host_name| self.find(host_name));
+        Some(found.unwrap_or_else(|| self.default.clone()))
+    }
+}
+
+/// Writes a self-signed certificate for `host_names` and its key as `{name}.pem` and
+/// `{name}.key` under `dir`, returning their paths and the DER of the certificate.
+#[cfg(test)]
+pub(crate) fn self_signed(
+    dir: &Path,
+    name: &str,
+    host_names: &[&str],
+) -> (std::path::PathBuf, std::path::PathBuf, Vec<u8>) {
+    let host_names = host_names
+        .iter()
+        .map(|name| name.to_string())
+        .collect::<Vec<_>>();
+    let rcgen::CertifiedKey { cert, key_pair } =
+        rcgen::generate_simple_self_signed(host_names).unwrap();
+    let cert_path = dir.join(format!("{}.pem", name));
+    let key_path = dir.join(format!("{}.key", name));
+    std::fs::write(&cert_path, cert.pem()).unwrap();
+    std::fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+    (cert_path, key_path, cert.der().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::static_files::TempDir;
+
+    #[test]
+    fn load_certificates() {
+        let dir = TempDir::new("tls");
+        let dir = &dir.0;
+        let (cert, key, der) = self_signed(dir, "default", &["localhost"]);
+        let (other_cert, other_key, other_der) = self_signed(dir, "other", &["*.example.com"]);
+
+        let config = TlsConfig::from_pem_files(&cert, &key)
+            .unwrap()
+            .with_sni_pem_files("*.example.com", &other_cert, &other_key)
+            .unwrap();
+        let resolver = &config.resolver;
+        let der_of =
+            |certified_key: Option<Arc<CertifiedKey>>| certified_key.unwrap().cert[0].to_vec();
+        assert_eq!(other_der, der_of(resolver.find("www.Example.com")));
+        assert!(resolver.find("example.com").is_none());
+        assert!(resolver.find("a.b.example.com").is_none());
+        assert_eq!(der, resolver.default.cert[0].to_vec());
+
+        // The key doesn't match the certificate
+        let e = TlsConfig::from_pem_files(&cert, &other_key).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, e.kind());
+        // Not a certificate
+        let e = TlsConfig::from_pem_files(&key, &key).err().unwrap();
+        assert!(e.to_string().contains("default.key"));
+        assert!(TlsConfig::from_pem_files(&dir.join("missing.pem"), &key).is_err());
+    }
+}